@@ -0,0 +1,471 @@
+//! Ahead-of-time compilation into native object files and executables.
+use crate::codegen::CompiledProgram;
+use crate::options::{Buffering, Eof};
+use crate::runtime::{ERROR_IO, ERROR_OUT_OF_BOUNDS, ERROR_OVERFLOW, ERROR_UNDERFLOW};
+use crate::{Error, Result};
+
+use inkwell::module::Linkage;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
+};
+use inkwell::values::CallSiteValue;
+use inkwell::AddressSpace;
+use inkwell::IntPredicate;
+
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// `dprintf` formats of the runtime errors, given the line, column and value.
+const RUNTIME_ERROR_MESSAGES: &[(u32, &str)] = &[
+    (
+        ERROR_OUT_OF_BOUNDS,
+        "runtime error: %u:%u: pointer out of bounds: %lld\n",
+    ),
+    (ERROR_OVERFLOW, "runtime error: %u:%u: cell overflow\n"),
+    (ERROR_UNDERFLOW, "runtime error: %u:%u: cell underflow\n"),
+    (ERROR_IO, "runtime error: %u:%u: I/O error\n"),
+];
+
+/// Output formats of the LLVM module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    LlvmIr,
+    Bitcode,
+    Assembly,
+}
+
+impl Emit {
+    /// File extension of the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::LlvmIr => "ll",
+            Emit::Bitcode => "bc",
+            Emit::Assembly => "s",
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "llvm-ir" => Ok(Emit::LlvmIr),
+            "bc" => Ok(Emit::Bitcode),
+            "asm" => Ok(Emit::Assembly),
+            _ => Err(format!("unknown emit type: {}", s)),
+        }
+    }
+}
+
+/// Creates a target machine for the host triple with a generic CPU, so that the
+/// produced objects run on any machine of the same architecture.
+pub(crate) fn host_target_machine(program: &CompiledProgram<'_>) -> Result<TargetMachine> {
+    Target::initialize_native(&InitializationConfig::default()).map_err(Error::Llvm)?;
+
+    let triple = TargetMachine::get_default_triple();
+    let target = Target::from_triple(&triple).map_err(|e| Error::Llvm(e.to_string()))?;
+
+    target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            program.optimization_level(),
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| Error::ice("failed to create target machine"))
+}
+
+impl<'c> CompiledProgram<'c> {
+    /// Writes the program as a native object file.
+    ///
+    /// The object defines a C `main` and only depends on `getchar`, `putchar`, `fflush`,
+    /// `calloc`, `dprintf`, `exit` and `memchr` from the C library.
+    pub fn write_object(&self, path: &Path) -> Result<()> {
+        self.build_main()?;
+
+        let target_machine = host_target_machine(self)?;
+        self.module().set_triple(&target_machine.get_triple());
+        self.module()
+            .set_data_layout(&target_machine.get_target_data().get_data_layout());
+
+        target_machine
+            .write_to_file(self.module(), FileType::Object, path)
+            .map_err(|e| Error::Llvm(e.to_string()))
+    }
+
+    /// Writes the module in the given format.
+    pub fn emit(&self, kind: Emit, path: &Path) -> Result<()> {
+        match kind {
+            Emit::LlvmIr => self
+                .module()
+                .print_to_file(path)
+                .map_err(|e| Error::Llvm(e.to_string())),
+            Emit::Bitcode => {
+                if !self.module().write_bitcode_to_path(path) {
+                    return Err(Error::Llvm(format!(
+                        "failed to write bitcode to {}",
+                        path.display()
+                    )));
+                }
+
+                Ok(())
+            }
+            Emit::Assembly => {
+                // code generation passes modify the IR; emit from a copy of the module
+                let module = self.module().clone();
+
+                let target_machine = host_target_machine(self)?;
+                module.set_triple(&target_machine.get_triple());
+                module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+
+                target_machine
+                    .write_to_file(&module, FileType::Assembly, path)
+                    .map_err(|e| Error::Llvm(e.to_string()))
+            }
+        }
+    }
+
+    /// Writes the program as a native executable.
+    ///
+    /// The object file is written into a temporary directory, so no file next to `path`
+    /// is overwritten but the executable.
+    pub fn write_executable(&self, path: &Path) -> Result<()> {
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+        // created anew, so that no other file is written through it
+        let dir = std::env::temp_dir().join(format!(
+            "bf-rs-{}-{}",
+            std::process::id(),
+            BUILDS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&dir)?;
+        let object = dir.join("program.o");
+
+        let res = self.write_object(&object).and_then(|_| {
+            self.options().log("linking...");
+            link(&object, path)
+        });
+        std::fs::remove_dir_all(&dir)?;
+
+        res
+    }
+
+    /// Builds `main` and the runtime functions passed to `bfrs_lang_start`.
+    fn build_main(&self) -> Result<()> {
+        let context = self.context();
+        let module = self.module();
+        let builder = context.create_builder();
+
+        // the executable always starts on a blank tape
+        let name = self.entry_name();
+        let entry = module
+            .get_function(name)
+            .ok_or_else(|| Error::ice(format!("{} is not compiled", name)))?;
+
+        // already built for a previous object
+        if module.get_function("main").is_some() {
+            return Ok(());
+        }
+
+        let i8_type = context.i8_type();
+        let i32_type = context.i32_type();
+        let i64_type = context.i64_type();
+        let ptr_type = i8_type.ptr_type(AddressSpace::Generic);
+
+        // C library
+        let libc_getchar = module.add_function(
+            "getchar",
+            i32_type.fn_type(&[], false),
+            Some(Linkage::External),
+        );
+        let libc_putchar = module.add_function(
+            "putchar",
+            i32_type.fn_type(&[i32_type.into()], false),
+            Some(Linkage::External),
+        );
+        let libc_fflush = module.add_function(
+            "fflush",
+            i32_type.fn_type(&[ptr_type.into()], false),
+            Some(Linkage::External),
+        );
+        let libc_calloc = module.add_function(
+            "calloc",
+            ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false),
+            Some(Linkage::External),
+        );
+        let libc_dprintf = module.add_function(
+            "dprintf",
+            i32_type.fn_type(&[i32_type.into(), ptr_type.into()], true),
+            Some(Linkage::External),
+        );
+        let libc_exit = module.add_function(
+            "exit",
+            context.void_type().fn_type(&[i32_type.into()], false),
+            Some(Linkage::External),
+        );
+
+        // runtime
+        let get_char = module.add_function(
+            "bfrs_get_char",
+            i64_type.fn_type(&[ptr_type.into(), i64_type.into()], false),
+            Some(Linkage::Private),
+        );
+        builder.position_at_end(context.append_basic_block(get_char, "entry"));
+        let current = get_char.get_nth_param(1).unwrap().into_int_value();
+        // the program may be waiting for the input to be prompted
+        builder.build_call(libc_fflush, &[ptr_type.const_null().into()], "");
+        let c = builder
+            .build_call(libc_getchar, &[], "")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let eof_value = match self.options().eof {
+            Eof::Unchanged => current,
+            eof => i64_type.const_int(eof.value(0, self.options().cell_width), false),
+        };
+        let c = builder.build_select(
+            builder.build_int_compare(IntPredicate::SLT, c, i32_type.const_int(0, false), ""),
+            eof_value,
+            builder.build_int_z_extend(c, i64_type, ""),
+            "",
+        );
+        builder.build_return(Some(&c));
+
+        let put_char = module.add_function(
+            "bfrs_print_char",
+            context
+                .void_type()
+                .fn_type(&[ptr_type.into(), i64_type.into()], false),
+            Some(Linkage::Private),
+        );
+        builder.position_at_end(context.append_basic_block(put_char, "entry"));
+        let env = put_char.get_nth_param(0).unwrap().into_pointer_value();
+        let c = put_char.get_nth_param(1).unwrap().into_int_value();
+        // sets the failure flag of the environment when a C library call returns EOF
+        let check = |res: CallSiteValue| {
+            let res = res.try_as_basic_value().left().unwrap().into_int_value();
+            let failed = builder.build_int_z_extend(
+                builder.build_int_compare(IntPredicate::SLT, res, i32_type.const_int(0, false), ""),
+                i8_type,
+                "",
+            );
+            let failed = builder.build_or(builder.build_load(env, "").into_int_value(), failed, "");
+            builder.build_store(env, failed);
+        };
+        check(builder.build_call(
+            libc_putchar,
+            &[builder.build_int_truncate(c, i32_type, "").into()],
+            "",
+        ));
+        // stdout is fully buffered by the C library unless it is a terminal
+        match self.options().buffering {
+            Buffering::Auto | Buffering::Full => {}
+            Buffering::Line => {
+                let flush = context.append_basic_block(put_char, "flush");
+                let end = context.append_basic_block(put_char, "end");
+                builder.build_conditional_branch(
+                    builder.build_int_compare(
+                        IntPredicate::EQ,
+                        c,
+                        i64_type.const_int(u64::from(b'\n'), false),
+                        "",
+                    ),
+                    flush,
+                    end,
+                );
+                builder.position_at_end(flush);
+                check(builder.build_call(libc_fflush, &[ptr_type.const_null().into()], ""));
+                builder.build_unconditional_branch(end);
+                builder.position_at_end(end);
+            }
+            Buffering::None => {
+                check(builder.build_call(libc_fflush, &[ptr_type.const_null().into()], ""));
+            }
+        }
+        builder.build_return(None);
+
+        // prints the error to stderr and exits
+        let runtime_error = module.add_function(
+            "bfrs_runtime_error",
+            context.void_type().fn_type(
+                &[
+                    ptr_type.into(),
+                    i32_type.into(),
+                    i64_type.into(),
+                    i64_type.into(),
+                ],
+                false,
+            ),
+            Some(Linkage::Private),
+        );
+        builder.position_at_end(context.append_basic_block(runtime_error, "entry"));
+        let position = runtime_error.get_nth_param(2).unwrap().into_int_value();
+        let value = runtime_error.get_nth_param(3).unwrap();
+        let line = builder.build_int_truncate(
+            builder.build_right_shift(position, i64_type.const_int(32, false), false, ""),
+            i32_type,
+            "",
+        );
+        let column = builder.build_int_truncate(position, i32_type, "");
+        let kind = runtime_error.get_nth_param(1).unwrap().into_int_value();
+        let mut message = ptr_type.const_null();
+        for &(error_kind, format) in RUNTIME_ERROR_MESSAGES {
+            let is_kind = builder.build_int_compare(
+                IntPredicate::EQ,
+                kind,
+                i32_type.const_int(error_kind as u64, false),
+                "",
+            );
+            let format = builder.build_global_string_ptr(format, "");
+            message = builder
+                .build_select(is_kind, format.as_pointer_value(), message, "")
+                .into_pointer_value();
+        }
+        builder.build_call(
+            libc_dprintf,
+            &[
+                i32_type.const_int(2, false).into(),
+                message.into(),
+                line.into(),
+                column.into(),
+                value,
+            ],
+            "",
+        );
+        builder.build_call(libc_exit, &[i32_type.const_int(1, false).into()], "");
+        builder.build_unreachable();
+
+        // loops over the cells for backward scans, as memrchr is not in every C library
+        if let Some(scan_back) = module.get_function("bfrs_scan_back") {
+            scan_back.set_linkage(Linkage::Private);
+            let tape = scan_back.get_nth_param(0).unwrap().into_pointer_value();
+            let start = scan_back.get_nth_param(1).unwrap().into_int_value();
+
+            let entry = context.append_basic_block(scan_back, "entry");
+            let head = context.append_basic_block(scan_back, "head");
+            let check = context.append_basic_block(scan_back, "check");
+            let step = context.append_basic_block(scan_back, "step");
+            let end = context.append_basic_block(scan_back, "end");
+
+            builder.position_at_end(entry);
+            builder.build_unconditional_branch(head);
+
+            // runs off the start of the tape at -1 without a zero
+            builder.position_at_end(head);
+            let phi = builder.build_phi(i64_type, "");
+            let pointer = phi.as_basic_value().into_int_value();
+            builder.build_conditional_branch(
+                builder.build_int_compare(
+                    IntPredicate::SLT,
+                    pointer,
+                    i64_type.const_int(0, false),
+                    "",
+                ),
+                end,
+                check,
+            );
+
+            builder.position_at_end(check);
+            let cell_ref = unsafe { builder.build_in_bounds_gep(tape, &[pointer], "") };
+            builder.build_conditional_branch(
+                builder.build_int_compare(
+                    IntPredicate::EQ,
+                    builder.build_load(cell_ref, "").into_int_value(),
+                    i8_type.const_int(0, false),
+                    "",
+                ),
+                end,
+                step,
+            );
+
+            builder.position_at_end(step);
+            let next = builder.build_int_sub(pointer, i64_type.const_int(1, false), "");
+            builder.build_unconditional_branch(head);
+
+            phi.add_incoming(&[(&start, entry), (&next, step)]);
+
+            builder.position_at_end(end);
+            builder.build_return(Some(&pointer));
+        }
+
+        // entry point
+        let main = module.add_function("main", i32_type.fn_type(&[], false), None);
+        builder.position_at_end(context.append_basic_block(main, "entry"));
+        let tape = builder
+            .build_call(
+                libc_calloc,
+                &[
+                    i64_type
+                        .const_int(self.options().tape_size as u64, false)
+                        .into(),
+                    i64_type
+                        .const_int(self.options().cell_width.bytes() as u64, false)
+                        .into(),
+                ],
+                "",
+            )
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+
+        let alloc_failed = context.append_basic_block(main, "");
+        let alloc_ok = context.append_basic_block(main, "");
+        builder.build_conditional_branch(
+            builder.build_is_null(tape.into_pointer_value(), ""),
+            alloc_failed,
+            alloc_ok,
+        );
+
+        builder.position_at_end(alloc_failed);
+        builder.build_return(Some(&i32_type.const_int(1, false)));
+
+        builder.position_at_end(alloc_ok);
+        // the environment only holds the failure flag checked after I/O
+        let env = builder.build_alloca(i8_type, "");
+        builder.build_store(env, i8_type.const_int(0, false));
+        // counted instructions are discarded
+        let counts_type = i64_type.array_type(8);
+        let counts = builder.build_alloca(counts_type, "");
+        builder.build_store(counts, counts_type.const_zero());
+        builder.build_call(
+            entry,
+            &[
+                env.into(),
+                get_char.as_global_value().as_pointer_value().into(),
+                put_char.as_global_value().as_pointer_value().into(),
+                runtime_error.as_global_value().as_pointer_value().into(),
+                tape,
+                i64_type.const_int(0, false).into(),
+                builder
+                    .build_pointer_cast(counts, i64_type.ptr_type(AddressSpace::Generic), "")
+                    .into(),
+            ],
+            "",
+        );
+        builder.build_return(Some(&i32_type.const_int(0, false)));
+
+        Ok(())
+    }
+}
+
+/// Links an object file into an executable with the system C compiler (`$CC`, or `cc`).
+pub fn link(object: &Path, output: &Path) -> Result<()> {
+    let linker = std::env::var_os("CC").unwrap_or_else(|| "cc".into());
+    let status = Command::new(linker)
+        .arg(object)
+        .arg("-o")
+        .arg(output)
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::Link(status));
+    }
+
+    Ok(())
+}
@@ -1,11 +1,21 @@
-use crate::parser::BfAST;
+use crate::ir::{self, MulTarget, Op};
+use crate::optimizer;
+use crate::options::{CellWidth, Options, Overflow};
+use crate::parser::{BfAST, Position};
+use crate::runtime::{
+    bfrs_get_char, bfrs_print_char, bfrs_runtime_error, bfrs_scan_back, execute_compiled, is_blank,
+    FinalState, MachineState, COUNT_ADD, COUNT_INPUT, COUNT_LEFT, COUNT_LOOP_END, COUNT_LOOP_START,
+    COUNT_OUTPUT, COUNT_RIGHT, COUNT_SUB, ERROR_IO, ERROR_OUT_OF_BOUNDS, ERROR_OVERFLOW,
+    ERROR_UNDERFLOW,
+};
 use crate::{Error, Result};
 
-use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
-use inkwell::module::Module;
+use inkwell::module::{Linkage, Module};
+use inkwell::passes::{PassManager, PassManagerBuilder};
+use inkwell::types::IntType;
 use inkwell::values::{FunctionValue, IntValue, PointerValue};
 use inkwell::AddressSpace;
 use inkwell::IntPredicate;
@@ -14,615 +24,788 @@ use inkwell::OptimizationLevel;
 // use crate::ice;
 
 use std::io::{Read, Write};
+use std::time::Instant;
 
-pub type BfBootstrap = unsafe extern "C" fn(
-    unsafe extern "C" fn() -> u8,
-    unsafe extern "C" fn(c: u8),
-    unsafe extern "C" fn(ptr: *mut u8, value: u8, len: u64),
-);
+pub use crate::runtime::BfBootstrap;
 
 pub struct Codegen<'c> {
     context: &'c Context,
-    module: Module<'c>,
     builder: Builder<'c>,
-    execution_engine: ExecutionEngine<'c>,
-}
-
-extern "C" fn bfrs_get_char() -> u8 {
-    let mut input = std::io::stdin();
-    let mut buf = [0u8];
-
-    if input.read(&mut buf).unwrap() == 0 {
-        return 0xFF; // EOF
-    }
-
-    buf[0]
+    options: Options,
 }
 
-extern "C" fn bfrs_print_char(c: u8) {
-    let mut out = std::io::stdout();
-    out.write_all(&[c]).unwrap();
-    out.flush().unwrap();
+/// A program compiled by [`Codegen::compile`], which can be executed any number of times.
+pub struct CompiledProgram<'c> {
+    context: &'c Context,
+    module: Module<'c>,
+    execution_engine: ExecutionEngine<'c>,
+    options: Options,
 }
 
-unsafe extern "C" fn bfrs_memset(ptr: *mut u8, value: u8, len: u64) {
-    std::ptr::write_bytes(ptr, value, len as usize);
+/// Runtime functions and the environment passed to `bfrs_lang_start`, and the module
+/// it is built in.
+#[derive(Clone, Copy)]
+struct RuntimeEnv<'m, 'c> {
+    module: &'m Module<'c>,
+    env: PointerValue<'c>,
+    get_char: PointerValue<'c>,
+    put_char: PointerValue<'c>,
+    runtime_error: PointerValue<'c>,
+    counter: PointerValue<'c>,
+    counts: PointerValue<'c>,
 }
 
 impl<'c> Codegen<'c> {
     pub fn new(context: &'c Context, optimized: bool) -> Result<Self> {
-        let module = context.create_module("bfrs");
+        Self::with_options(
+            context,
+            Options {
+                optimized,
+                ..Options::default()
+            },
+        )
+    }
 
-        let execution_engine = module
-            .create_jit_execution_engine(if optimized {
-                OptimizationLevel::Aggressive
-            } else {
-                OptimizationLevel::None
-            })
-            .map_err(|_| Error::Ice("failed to create execution engine".into()))?;
+    pub fn with_options(context: &'c Context, options: Options) -> Result<Self> {
         let builder = context.create_builder();
 
         Ok(Self {
             context,
-            module,
-            execution_engine,
             builder,
+            options,
         })
     }
 
+    pub fn context(&self) -> &'c Context {
+        self.context
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Integer type of a cell.
+    pub fn cell_type(&self) -> IntType<'c> {
+        self.context
+            .custom_width_int_type(self.options.cell_width.bits())
+    }
+
+    /// Compiles and runs the program.
     pub fn run(&self, ast: &[BfAST]) -> Result<()> {
+        let program = self.compile(ast)?;
+        program.optimize();
+        program.execute().map(|_| ())
+    }
+
+    /// Builds the entry point `bfrs_lang_start` of the program into a new module, and
+    /// `bfrs_lang_main` for a blank tape if that removes anything.
+    ///
+    /// The module is not optimized until [`CompiledProgram::optimize`] is called.
+    pub fn compile(&self, ast: &[BfAST]) -> Result<CompiledProgram<'c>> {
+        self.options.log("compiling...");
+
+        let module = self.context.create_module("bfrs");
+
+        let ops = optimizer::optimize(ir::lower(ast, &self.options), &self.options);
+        let blank_ops = optimizer::specialize_blank(ast, &self.options);
+
+        self.build_entry(&module, "bfrs_lang_start", &ops)?;
+        if blank_ops != ops {
+            // programs failing to build for a blank tape still start from bfrs_lang_start
+            if let Err(e) = self.build_entry(&module, "bfrs_lang_main", &blank_ops) {
+                self.options
+                    .log(&format!("not specialized for a blank tape: {}", e));
+                if let Some(function) = module.get_function("bfrs_lang_main") {
+                    unsafe { function.delete() };
+                }
+            }
+        }
+
+        let execution_engine = module
+            .create_jit_execution_engine(optimization_level(&self.options))
+            .map_err(|_| Error::ice("failed to create execution engine"))?;
+        // executables define the backward scans themselves
+        if let Some(function) = module.get_function("bfrs_scan_back") {
+            execution_engine.add_global_mapping(&function, bfrs_scan_back as usize);
+        }
+
+        Ok(CompiledProgram {
+            context: self.context,
+            module,
+            execution_engine,
+            options: self.options.clone(),
+        })
+    }
+
+    /// Builds an entry point named `name` running the operations into the module.
+    fn build_entry(&self, module: &Module<'c>, name: &str, ops: &[Op]) -> Result<()> {
         // 実行環境の構築
+        let env_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
         let get_char_type = self
             .context
-            .i8_type()
-            .fn_type(&[], false)
-            .ptr_type(AddressSpace::Global);
+            .i64_type()
+            .fn_type(&[env_type.into(), self.context.i64_type().into()], false)
+            .ptr_type(AddressSpace::Generic);
 
         let put_char_type = self
             .context
             .void_type()
-            .fn_type(&[self.context.i8_type().into()], false)
-            .ptr_type(AddressSpace::Global);
+            .fn_type(&[env_type.into(), self.context.i64_type().into()], false)
+            .ptr_type(AddressSpace::Generic);
 
-        let memset_type = self
+        let runtime_error_type = self
             .context
             .void_type()
             .fn_type(
                 &[
-                    self.context
-                        .i8_type()
-                        .ptr_type(AddressSpace::Generic)
-                        .into(),
-                    self.context.i8_type().into(),
+                    env_type.into(),
+                    self.context.i32_type().into(),
+                    self.context.i64_type().into(),
                     self.context.i64_type().into(),
                 ],
                 false,
             )
-            .ptr_type(AddressSpace::Global);
+            .ptr_type(AddressSpace::Generic);
+
+        let tape_type = self.cell_type().ptr_type(AddressSpace::Generic);
+        let counts_type = self.context.i64_type().ptr_type(AddressSpace::Generic);
 
-        let fn_type = self.context.void_type().fn_type(
+        // returns the final pointer
+        let fn_type = self.context.i64_type().fn_type(
             &[
+                env_type.into(),
                 get_char_type.into(),
                 put_char_type.into(),
-                memset_type.into(),
+                runtime_error_type.into(),
+                tape_type.into(),
+                self.context.i64_type().into(),
+                counts_type.into(),
             ],
             false,
         );
 
-        let func = self.module.add_function("bfrs_lang_start", fn_type, None);
+        let func = module.add_function(name, fn_type, None);
 
         let basic_block = self.context.append_basic_block(func, "entry");
 
         self.builder.position_at_end(basic_block);
 
-        let get_char = func.get_nth_param(0).unwrap().into_pointer_value();
-        let put_char = func.get_nth_param(1).unwrap().into_pointer_value();
-        let memset = func.get_nth_param(2).unwrap().into_pointer_value();
-
-        let value_table = self.context.i8_type().array_type(10000);
-        let value_table = self.builder.build_alloca(value_table, "");
+        let value_table = func.get_nth_param(4).unwrap().into_pointer_value();
         let counter = self.builder.build_alloca(self.context.i64_type(), "");
 
         self.builder
-            .build_store(counter, self.context.i64_type().const_int(0, false));
+            .build_store(counter, func.get_nth_param(5).unwrap().into_int_value());
 
-        self.builder.build_call(
-            memset,
-            &[
-                value_table.into(),
-                self.context.i8_type().const_int(0, false).into(),
-                self.context.i64_type().const_int(10000, false).into(),
-            ],
-            "",
-        );
+        let env = RuntimeEnv {
+            module,
+            env: func.get_nth_param(0).unwrap().into_pointer_value(),
+            get_char: func.get_nth_param(1).unwrap().into_pointer_value(),
+            put_char: func.get_nth_param(2).unwrap().into_pointer_value(),
+            runtime_error: func.get_nth_param(3).unwrap().into_pointer_value(),
+            counter,
+            counts: func.get_nth_param(6).unwrap().into_pointer_value(),
+        };
 
-        for op in ast {
-            self.build_operation(func, (get_char, put_char), op, value_table, counter)?;
+        for op in ops {
+            self.build_operation(func, env, op, value_table, counter)?;
         }
 
-        self.builder.build_return(None);
-
-        print!("building...");
-        std::io::stdout().flush().unwrap();
-
-        let entry: JitFunction<BfBootstrap> =
-            unsafe { self.execution_engine.get_function("bfrs_lang_start") }.unwrap();
-
-        print!("\u{001b}[2K\r");
-        std::io::stdout().flush().unwrap();
-
-        unsafe {
-            entry.call(bfrs_get_char, bfrs_print_char, bfrs_memset);
-        }
+        self.build_return(env);
 
         Ok(())
     }
 
-    fn balanced_loop_optimization(
+    /// Builds a multiplication loop as straight-line multiply-adds of its number of
+    /// iterations, which only touch the targets if the loop is entered.
+    #[allow(clippy::too_many_arguments)]
+    fn build_mul_loop(
         &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
         value_table: PointerValue<'c>,
         counter: PointerValue<'c>,
-        v: &[BfAST],
-    ) -> Result<bool> {
-        // TODO: too dirty; needs to refactor
-
-        if let [BfAST::AddPtr(j), BfAST::AddOp(k), BfAST::SubPtr(l), BfAST::SubOp(1)] = v[0..4] {
-            if j == l {
-                let rhs = self.get_current(value_table, counter);
-
-                let dest_pos = self.builder.build_int_add(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
+        step: u64,
+        targets: &[MulTarget],
+    ) {
+        let zero = self.cell_type().const_int(0, false);
+        let cur = self.get_current(value_table, counter);
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+        let br_entered = self.context.append_basic_block(function, "");
+        let br_end = self.context.append_basic_block(function, "");
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
+        self.builder.build_conditional_branch(
+            self.builder
+                .build_int_compare(IntPredicate::EQ, cur, zero, ""),
+            br_end,
+            br_entered,
+        );
 
-                let res = if k != 1 {
-                    let k = self.context.i8_type().const_int(k as u64, false);
+        self.builder.position_at_end(br_entered);
 
-                    self.builder
-                        .build_int_add(dest, self.builder.build_int_mul(k, rhs, ""), "")
-                } else {
-                    self.builder.build_int_add(dest, rhs, "")
-                };
+        let iterations = if step == 1 {
+            cur
+        } else {
+            self.build_iterations(function, cur, step)
+        };
 
-                self.builder.build_store(dest_ref, res);
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+        for target in targets {
+            let dest_ref = self.get_cell_ref(value_table, counter, target.offset);
+            let dest = self.builder.build_load(dest_ref, "").into_int_value();
+
+            let res = self.build_mul_add(
+                function,
+                env,
+                dest,
+                Some(iterations),
+                target.factor,
+                target.subtract,
+                target.position,
+            );
+
+            self.builder.build_store(dest_ref, res);
+        }
 
-                return Ok(true);
-            }
-        } else if let [BfAST::SubPtr(j), BfAST::AddOp(k), BfAST::AddPtr(l), BfAST::SubOp(1)] =
-            v[0..4]
-        {
-            if j == l {
-                let rhs = self.get_current(value_table, counter);
-
-                let dest_pos = self.builder.build_int_sub(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
+        self.set_current(value_table, counter, zero);
+        self.builder.build_unconditional_branch(br_end);
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+        self.builder.position_at_end(br_end);
+    }
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
+    /// Computes the number of iterations of a loop subtracting `step` from the non-zero `value`
+    /// until it is zero.
+    fn build_iterations(
+        &self,
+        function: FunctionValue<'c>,
+        value: IntValue<'c>,
+        step: u64,
+    ) -> IntValue<'c> {
+        let cell_type = self.cell_type();
+        let max = self.options.cell_width.max_value();
 
-                let res = if k != 1 {
-                    let k = self.context.i8_type().const_int(k as u64, false);
+        if self.options.overflow == Overflow::Wrap {
+            // value = step * n modulo the cell size, where step = odd << shift
+            let shift = step.trailing_zeros();
+            let odd = step >> shift;
 
+            if shift != 0 {
+                let remainder =
                     self.builder
-                        .build_int_add(dest, self.builder.build_int_mul(k, rhs, ""), "")
-                } else {
-                    self.builder.build_int_add(dest, rhs, "")
-                };
-
-                self.builder.build_store(dest_ref, res);
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
-
-                return Ok(true);
+                        .build_and(value, cell_type.const_int((1 << shift) - 1, false), "");
+                self.build_forever_if(function, remainder);
             }
-        } else if let [BfAST::AddPtr(j), BfAST::SubOp(k), BfAST::SubPtr(l), BfAST::SubOp(1)] =
-            v[0..4]
-        {
-            if j == l {
-                let rhs = self.get_current(value_table, counter);
-
-                let dest_pos = self.builder.build_int_add(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
-
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
-
-                let res = if k != 1 {
-                    let k = self.context.i8_type().const_int(k as u64, false);
+            let inverse = optimizer::modular_inverse(odd) & max;
+            let value = self.builder.build_right_shift(
+                value,
+                cell_type.const_int(u64::from(shift), false),
+                false,
+                "",
+            );
+            let n = self
+                .builder
+                .build_int_mul(value, cell_type.const_int(inverse, false), "");
+            return self
+                .builder
+                .build_and(n, cell_type.const_int(max >> shift, false), "");
+        }
 
-                    self.builder
-                        .build_int_sub(dest, self.builder.build_int_mul(k, rhs, ""), "")
-                } else {
-                    self.builder.build_int_sub(dest, rhs, "")
-                };
+        // the cell saturates at zero in the first iteration
+        if step > max {
+            return cell_type.const_int(1, false);
+        }
 
-                self.builder.build_store(dest_ref, res);
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+        // rounds up, as the last iteration saturates
+        let step = cell_type.const_int(step, false);
+        let quotient = self.builder.build_int_unsigned_div(value, step, "");
+        let remainder = self.builder.build_int_unsigned_rem(value, step, "");
+        let rounded = self.builder.build_int_compare(
+            IntPredicate::NE,
+            remainder,
+            cell_type.const_int(0, false),
+            "",
+        );
+        let rounded = self.builder.build_int_z_extend(rounded, cell_type, "");
+        self.builder.build_int_add(quotient, rounded, "")
+    }
 
-                return Ok(true);
-            }
-        } else if let [BfAST::SubPtr(j), BfAST::SubOp(k), BfAST::AddPtr(l), BfAST::SubOp(1)] =
-            v[0..4]
-        {
-            if j == l {
-                let rhs = self.get_current(value_table, counter);
-
-                let dest_pos = self.builder.build_int_sub(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
+    /// Runs forever if `condition` is not zero, like a loop never reaching zero.
+    fn build_forever_if(&self, function: FunctionValue<'c>, condition: IntValue<'c>) {
+        let br_okay = self.context.append_basic_block(function, "");
+        let br_forever = self.context.append_basic_block(function, "");
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::EQ,
+                condition,
+                condition.get_type().const_int(0, false),
+                "",
+            ),
+            br_okay,
+            br_forever,
+        );
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
+        self.builder.position_at_end(br_forever);
+        self.builder.build_unconditional_branch(br_forever);
 
-                let res = if k != 1 {
-                    let k = self.context.i8_type().const_int(k as u64, false);
+        self.builder.position_at_end(br_okay);
+    }
 
+    /// Clears the current cell, if the clear loop terminates on it.
+    fn build_clear(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        value_table: PointerValue<'c>,
+        counter: PointerValue<'c>,
+        divisor: u64,
+        position: Position,
+    ) {
+        let cell_type = self.cell_type();
+
+        if divisor == 0 {
+            // the loop never steps the cell, so only terminates on zero
+            let cur = self.get_current(value_table, counter);
+            self.build_forever_if(function, cur);
+        } else if divisor != 1 {
+            let cur = self.get_current(value_table, counter);
+
+            if self.options.overflow == Overflow::Trap {
+                // underflows unless the cell is a multiple of the step
+                self.build_step_check(function, env, cur, divisor, position);
+            } else {
+                // the divisor is a power of two with wrapping
+                let remainder =
                     self.builder
-                        .build_int_sub(dest, self.builder.build_int_mul(k, rhs, ""), "")
-                } else {
-                    self.builder.build_int_sub(dest, rhs, "")
-                };
-
-                self.builder.build_store(dest_ref, res);
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
-
-                return Ok(true);
+                        .build_and(cur, cell_type.const_int(divisor - 1, false), "");
+                self.build_forever_if(function, remainder);
             }
         }
 
-        return Ok(false);
+        self.set_current(value_table, counter, cell_type.const_int(0, false));
     }
 
-    fn div_optimization(
+    /// Moves the pointer by `stride` until the current cell is zero, searching the tape
+    /// with `memchr` for forward scans of byte cells, and with `bfrs_scan_back` of the runtime
+    /// for backward ones. Other scans loop over the cells.
+    fn build_scan(
         &self,
         function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
         value_table: PointerValue<'c>,
         counter: PointerValue<'c>,
-        v: &[BfAST],
-        loop_end: &BasicBlock,
-    ) -> Result<bool> {
-        // TODO: too dirty; needs to refactor
-
-        if let [BfAST::SubOp(i), BfAST::AddPtr(j), BfAST::AddOp(1), BfAST::SubPtr(k)] = v[0..4] {
-            if j == k {
-                let cur = self.get_current(value_table, counter);
-                let rat = self.context.i8_type().const_int(i as u64, false);
-
-                let modulo = self.builder.build_int_unsigned_rem(cur, rat, "");
-
-                let br_okay = self.context.append_basic_block(function, "");
-                let br_not_okay = self.context.append_basic_block(function, "");
-
-                self.builder.build_conditional_branch(
-                    self.builder.build_int_compare(
-                        IntPredicate::EQ,
-                        modulo,
-                        self.context.i8_type().const_int(0 as u64, false),
-                        "",
-                    ),
-                    br_okay,
-                    br_not_okay,
-                );
-
-                self.builder.position_at_end(br_okay);
-
-                let dest_pos = self.builder.build_int_add(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
+        stride: i64,
+    ) {
+        let i64_type = self.context.i64_type();
+
+        if stride == 1 && self.options.cell_width == CellWidth::U8 {
+            let i32_type = self.context.i32_type();
+            let ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+            let memchr = env.module.get_function("memchr").unwrap_or_else(|| {
+                env.module.add_function(
+                    "memchr",
+                    ptr_type.fn_type(&[ptr_type.into(), i32_type.into(), i64_type.into()], false),
+                    None,
+                )
+            });
+
+            let tape_size = i64_type.const_int(self.options.tape_size as u64, false);
+            let pointer = self.builder.build_load(counter, "").into_int_value();
+            let start = self.get_cell_ref(value_table, counter, 0);
+            let len = self.builder.build_int_sub(tape_size, pointer, "");
+
+            let found = self
+                .builder
+                .build_call(
+                    memchr,
+                    &[
+                        start.into(),
+                        i32_type.const_int(0, false).into(),
+                        len.into(),
+                    ],
                     "",
-                );
+                )
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_pointer_value();
+
+            // runs off the end of the tape without a zero
+            let index = self.builder.build_int_sub(
+                self.builder.build_ptr_to_int(found, i64_type, ""),
+                self.builder.build_ptr_to_int(value_table, i64_type, ""),
+                "",
+            );
+            let pointer = self
+                .builder
+                .build_select(self.builder.build_is_null(found, ""), tape_size, index, "")
+                .into_int_value();
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+            self.builder.build_store(counter, pointer);
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
-                let res = self.builder.build_int_add(
-                    dest,
-                    self.builder.build_int_unsigned_div(cur, rat, ""),
-                    "",
-                );
+            return;
+        }
 
-                self.builder.build_store(dest_ref, res);
+        if stride == -1 && self.options.cell_width == CellWidth::U8 {
+            let ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+            let scan_back = env
+                .module
+                .get_function("bfrs_scan_back")
+                .unwrap_or_else(|| {
+                    env.module.add_function(
+                        "bfrs_scan_back",
+                        i64_type.fn_type(&[ptr_type.into(), i64_type.into()], false),
+                        None,
+                    )
+                });
 
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+            let pointer = self.builder.build_load(counter, "").into_int_value();
+            let pointer = self
+                .builder
+                .build_call(scan_back, &[value_table.into(), pointer.into()], "")
+                .try_as_basic_value()
+                .left()
+                .unwrap()
+                .into_int_value();
 
-                self.builder.build_unconditional_branch(loop_end.clone());
+            self.builder.build_store(counter, pointer);
 
-                self.builder.position_at_end(br_not_okay);
+            return;
+        }
 
-                return Ok(true);
-            }
-        } else if let [BfAST::SubOp(i), BfAST::SubPtr(j), BfAST::AddOp(1), BfAST::AddPtr(k)] =
-            v[0..4]
-        {
-            if j == k {
-                let cur = self.get_current(value_table, counter);
-                let rat = self.context.i8_type().const_int(i as u64, false);
+        let br_pre = self.builder.get_insert_block().unwrap();
+        let start = self.builder.build_load(counter, "").into_int_value();
 
-                let modulo = self.builder.build_int_unsigned_rem(cur, rat, "");
+        let br_head = self.context.append_basic_block(function, "");
+        let br_step = self.context.append_basic_block(function, "");
+        let br_end = self.context.append_basic_block(function, "");
 
-                let br_okay = self.context.append_basic_block(function, "");
-                let br_not_okay = self.context.append_basic_block(function, "");
+        self.builder.build_unconditional_branch(br_head);
 
-                self.builder.build_conditional_branch(
-                    self.builder.build_int_compare(
-                        IntPredicate::EQ,
-                        modulo,
-                        self.context.i8_type().const_int(0 as u64, false),
-                        "",
-                    ),
-                    br_okay,
-                    br_not_okay,
-                );
-
-                self.builder.position_at_end(br_okay);
+        // the pointer stays in a register during the scan
+        self.builder.position_at_end(br_head);
+        let phi = self.builder.build_phi(i64_type, "");
+        let pointer = phi.as_basic_value().into_int_value();
+        let cell_ref = unsafe {
+            self.builder
+                .build_in_bounds_gep(value_table, &[pointer], "")
+        };
 
-                let dest_pos = self.builder.build_int_sub(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::EQ,
+                self.builder.build_load(cell_ref, "").into_int_value(),
+                self.cell_type().const_int(0, false),
+                "",
+            ),
+            br_end,
+            br_step,
+        );
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+        self.builder.position_at_end(br_step);
+        let next = self
+            .builder
+            .build_int_add(pointer, i64_type.const_int(stride as u64, true), "");
+        self.builder.build_unconditional_branch(br_head);
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
-                let res = self.builder.build_int_add(
-                    dest,
-                    self.builder.build_int_unsigned_div(cur, rat, ""),
-                    "",
-                );
+        phi.add_incoming(&[(&start, br_pre), (&next, br_step)]);
 
-                self.builder.build_store(dest_ref, res);
+        self.builder.position_at_end(br_end);
+        self.builder.build_store(counter, pointer);
+    }
 
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+    /// Writes the bytes from a constant in the module.
+    fn build_output(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        bytes: &[u8],
+        position: Position,
+    ) {
+        let i8_type = self.context.i8_type();
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_int(0, false);
+
+        let values: Vec<_> = bytes
+            .iter()
+            .map(|&c| i8_type.const_int(u64::from(c), false))
+            .collect();
+        let global = env
+            .module
+            .add_global(i8_type.array_type(bytes.len() as u32), None, "");
+        global.set_initializer(&i8_type.const_array(&values));
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+
+        let br_pre = self.builder.get_insert_block().unwrap();
+        let br_head = self.context.append_basic_block(function, "");
+        let br_end = self.context.append_basic_block(function, "");
+
+        self.builder.build_unconditional_branch(br_head);
+
+        // the bytes are not empty
+        self.builder.position_at_end(br_head);
+        let phi = self.builder.build_phi(i64_type, "");
+        let index = phi.as_basic_value().into_int_value();
+        let byte_ref = unsafe {
+            self.builder
+                .build_in_bounds_gep(global.as_pointer_value(), &[zero, index], "")
+        };
+        let c = self.builder.build_load(byte_ref, "").into_int_value();
+        let c = self.builder.build_int_z_extend(c, i64_type, "");
+        self.builder
+            .build_call(env.put_char, &[env.env.into(), c.into()], "");
+        self.build_io_check(function, env, position);
+
+        let next = self
+            .builder
+            .build_int_add(index, i64_type.const_int(1, false), "");
+        let br_step = self.builder.get_insert_block().unwrap();
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::ULT,
+                next,
+                i64_type.const_int(bytes.len() as u64, false),
+                "",
+            ),
+            br_head,
+            br_end,
+        );
 
-                self.builder.build_unconditional_branch(*loop_end);
+        phi.add_incoming(&[(&zero, br_pre), (&next, br_step)]);
 
-                self.builder.position_at_end(br_not_okay);
+        self.builder.position_at_end(br_end);
+    }
 
-                return Ok(true);
-            }
-        } else if let [BfAST::SubOp(i), BfAST::AddPtr(j), BfAST::SubOp(1), BfAST::SubPtr(k)] =
-            v[0..4]
-        {
-            if j == k {
-                let cur = self.get_current(value_table, counter);
-                let rat = self.context.i8_type().const_int(i as u64, false);
+    /// Stores the cells from the current one on from a constant in the module.
+    fn build_tape(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        value_table: PointerValue<'c>,
+        counter: PointerValue<'c>,
+        cells: &[u64],
+    ) {
+        let cell_type = self.cell_type();
+        let i64_type = self.context.i64_type();
+        let zero = i64_type.const_int(0, false);
+
+        let values: Vec<_> = cells
+            .iter()
+            .map(|&cell| cell_type.const_int(cell, false))
+            .collect();
+        let global = env
+            .module
+            .add_global(cell_type.array_type(cells.len() as u32), None, "");
+        global.set_initializer(&cell_type.const_array(&values));
+        global.set_constant(true);
+        global.set_linkage(Linkage::Private);
+
+        let start = self.get_cell_ref(value_table, counter, 0);
+
+        let br_pre = self.builder.get_insert_block().unwrap();
+        let br_head = self.context.append_basic_block(function, "");
+        let br_end = self.context.append_basic_block(function, "");
+
+        self.builder.build_unconditional_branch(br_head);
+
+        // the cells are not empty, and copied like memcpy
+        self.builder.position_at_end(br_head);
+        let phi = self.builder.build_phi(i64_type, "");
+        let index = phi.as_basic_value().into_int_value();
+        let src = unsafe {
+            self.builder
+                .build_in_bounds_gep(global.as_pointer_value(), &[zero, index], "")
+        };
+        let dest = unsafe { self.builder.build_in_bounds_gep(start, &[index], "") };
+        let cell = self.builder.build_load(src, "");
+        self.builder.build_store(dest, cell);
+
+        let next = self
+            .builder
+            .build_int_add(index, i64_type.const_int(1, false), "");
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::ULT,
+                next,
+                i64_type.const_int(cells.len() as u64, false),
+                "",
+            ),
+            br_head,
+            br_end,
+        );
 
-                let modulo = self.builder.build_int_unsigned_rem(cur, rat, "");
+        phi.add_incoming(&[(&zero, br_pre), (&next, br_head)]);
 
-                let br_okay = self.context.append_basic_block(function, "");
-                let br_not_okay = self.context.append_basic_block(function, "");
+        self.builder.position_at_end(br_end);
+    }
 
-                self.builder.build_conditional_branch(
-                    self.builder.build_int_compare(
-                        IntPredicate::EQ,
-                        modulo,
-                        self.context.i8_type().const_int(0 as u64, false),
-                        "",
-                    ),
-                    br_okay,
-                    br_not_okay,
-                );
+    /// Computes `value + factor * count` (or `value - factor * count` if `subtract`), where
+    /// `count` defaults to 1, under the overflow semantics of the options.
+    #[allow(clippy::too_many_arguments)]
+    fn build_mul_add(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        value: IntValue<'c>,
+        count: Option<IntValue<'c>>,
+        factor: u64,
+        subtract: bool,
+        position: Position,
+    ) -> IntValue<'c> {
+        let cell_type = self.cell_type();
 
-                self.builder.position_at_end(br_okay);
+        if self.options.overflow == Overflow::Wrap {
+            let factor = cell_type.const_int(factor, false);
+            let delta = match count {
+                Some(count) => self.builder.build_int_mul(count, factor, ""),
+                None => factor,
+            };
 
-                let dest_pos = self.builder.build_int_add(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
+            return if subtract {
+                self.builder.build_int_sub(value, delta, "")
+            } else {
+                self.builder.build_int_add(value, delta, "")
+            };
+        }
 
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
+        let bool_type = self.context.bool_type();
 
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
-                let res = self.builder.build_int_sub(
-                    dest,
-                    self.builder.build_int_unsigned_div(cur, rat, ""),
+        // the delta, and whether it does not fit in a cell
+        let (delta, delta_overflow) = if factor > self.options.cell_width.max_value() {
+            let overflow = match count {
+                Some(count) => self.builder.build_int_compare(
+                    IntPredicate::NE,
+                    count,
+                    cell_type.const_int(0, false),
                     "",
-                );
+                ),
+                None => bool_type.const_int(1, false),
+            };
 
-                self.builder.build_store(dest_ref, res);
+            (cell_type.const_all_ones(), overflow)
+        } else {
+            let factor = cell_type.const_int(factor, false);
 
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+            match count {
+                Some(count) => self.build_with_overflow(env, "umul", count, factor),
+                None => (factor, bool_type.const_int(0, false)),
+            }
+        };
 
-                self.builder.build_unconditional_branch(*loop_end);
+        let (res, overflow) =
+            self.build_with_overflow(env, if subtract { "usub" } else { "uadd" }, value, delta);
+        let overflow = self.builder.build_or(overflow, delta_overflow, "");
 
-                self.builder.position_at_end(br_not_okay);
+        match self.options.overflow {
+            Overflow::Wrap => unreachable!(),
+            Overflow::Saturate => {
+                let bound = if subtract {
+                    cell_type.const_int(0, false)
+                } else {
+                    cell_type.const_all_ones()
+                };
 
-                return Ok(true);
+                self.builder
+                    .build_select(overflow, bound, res, "")
+                    .into_int_value()
             }
-        } else if let [BfAST::SubOp(i), BfAST::SubPtr(j), BfAST::SubOp(1), BfAST::AddPtr(k)] =
-            v[0..4]
-        {
-            if j == k {
-                let cur = self.get_current(value_table, counter);
-                let rat = self.context.i8_type().const_int(i as u64, false);
-
-                let modulo = self.builder.build_int_unsigned_rem(cur, rat, "");
-
+            Overflow::Trap => {
                 let br_okay = self.context.append_basic_block(function, "");
-                let br_not_okay = self.context.append_basic_block(function, "");
-
-                self.builder.build_conditional_branch(
-                    self.builder.build_int_compare(
-                        IntPredicate::EQ,
-                        modulo,
-                        self.context.i8_type().const_int(0 as u64, false),
-                        "",
-                    ),
-                    br_okay,
-                    br_not_okay,
+                let br_overflow = self.context.append_basic_block(function, "");
+
+                self.builder
+                    .build_conditional_branch(overflow, br_overflow, br_okay);
+
+                self.builder.position_at_end(br_overflow);
+                self.build_runtime_error(
+                    env,
+                    if subtract {
+                        ERROR_UNDERFLOW
+                    } else {
+                        ERROR_OVERFLOW
+                    },
+                    position,
+                    self.context.i64_type().const_int(0, false),
                 );
 
                 self.builder.position_at_end(br_okay);
 
-                let dest_pos = self.builder.build_int_sub(
-                    self.builder.build_load(counter, "").into_int_value(),
-                    self.context.i64_type().const_int(j as u64, false),
-                    "",
-                );
-
-                let dest_ref = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        value_table,
-                        &[self.context.i64_type().const_int(0, false), dest_pos],
-                        "",
-                    )
-                };
-
-                let dest = self.builder.build_load(dest_ref, "").into_int_value();
-                let res = self.builder.build_int_sub(
-                    dest,
-                    self.builder.build_int_unsigned_div(cur, rat, ""),
-                    "",
-                );
-
-                self.builder.build_store(dest_ref, res);
-
-                self.set_current(
-                    value_table,
-                    counter,
-                    self.context.i8_type().const_int(0, false),
-                );
+                res
+            }
+        }
+    }
 
-                self.builder.build_unconditional_branch(*loop_end);
+    /// Calls `llvm.<op>.with.overflow` on cells, returning the result and the overflow bit.
+    fn build_with_overflow(
+        &self,
+        env: RuntimeEnv<'_, 'c>,
+        op: &str,
+        lhs: IntValue<'c>,
+        rhs: IntValue<'c>,
+    ) -> (IntValue<'c>, IntValue<'c>) {
+        let cell_type = self.cell_type();
+        let name = format!(
+            "llvm.{}.with.overflow.i{}",
+            op,
+            self.options.cell_width.bits()
+        );
 
-                self.builder.position_at_end(br_not_okay);
+        let intrinsic = env.module.get_function(&name).unwrap_or_else(|| {
+            let ret_type = self
+                .context
+                .struct_type(&[cell_type.into(), self.context.bool_type().into()], false);
 
-                return Ok(true);
-            }
-        }
-        return Ok(false);
+            env.module.add_function(
+                &name,
+                ret_type.fn_type(&[cell_type.into(), cell_type.into()], false),
+                None,
+            )
+        });
+
+        let res = self
+            .builder
+            .build_call(intrinsic, &[lhs.into(), rhs.into()], "")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+
+        (
+            self.builder
+                .build_extract_value(res, 0, "")
+                .unwrap()
+                .into_int_value(),
+            self.builder
+                .build_extract_value(res, 1, "")
+                .unwrap()
+                .into_int_value(),
+        )
     }
 
     fn build_operation(
         &self,
         function: FunctionValue<'c>,
-        env: (PointerValue<'c>, PointerValue<'c>),
-        operation: &BfAST,
+        env: RuntimeEnv<'_, 'c>,
+        operation: &Op,
         value_table: PointerValue<'c>,
         counter: PointerValue<'c>,
     ) -> Result<()> {
-        let cur = self.get_current(value_table, counter);
-
         match operation {
-            BfAST::LoopBlock(v) => {
-                // 特殊パターンの高速化
-                if v.is_empty() {
-                    return Ok(());
-                } else if v.len() == 1 {
-                    if let BfAST::SubOp(_) = v[0] {
-                        self.set_current(
-                            value_table,
-                            counter,
-                            self.context.i8_type().const_int(0 as u64, false),
-                        );
-
-                        return Ok(());
-                    }
-                } else if v.len() == 4 {
-                    // balanced loop optimization (frequently used on multiplications)
-                    if self.balanced_loop_optimization(value_table, counter, &v)? {
-                        return Ok(());
-                    }
-                }
+            Op::Loop(v, _) | Op::EnteredLoop(v, _) => {
+                self.build_count(env, COUNT_LOOP_START, 1);
 
                 let loop_head = self.context.append_basic_block(function, "");
                 let loop_body = self.context.append_basic_block(function, "");
                 let loop_end = self.context.append_basic_block(function, "");
 
-                if v.len() == 4 {
-                    // division optimization
-                    self.div_optimization(function, value_table, counter, &v, &loop_end)?;
-                }
-
-                self.builder.build_unconditional_branch(loop_head);
+                // an entered loop skips the first check
+                self.builder.build_unconditional_branch(match operation {
+                    Op::EnteredLoop(..) => loop_body,
+                    _ => loop_head,
+                });
 
                 self.builder.position_at_end(loop_head);
 
@@ -630,7 +813,7 @@ impl<'c> Codegen<'c> {
                     self.builder.build_int_compare(
                         IntPredicate::EQ,
                         self.get_current(value_table, counter),
-                        self.context.i8_type().const_int(0, false),
+                        self.cell_type().const_int(0, false),
                         "",
                     ),
                     loop_end,
@@ -643,63 +826,279 @@ impl<'c> Codegen<'c> {
                     self.build_operation(function, env, i, value_table, counter)?;
                 }
 
+                self.build_count(env, COUNT_LOOP_END, 1);
                 self.builder.build_unconditional_branch(loop_head);
 
                 self.builder.position_at_end(loop_end);
             }
-            BfAST::AddOp(k) => {
-                let cur = self.builder.build_int_add(
-                    cur,
-                    self.context.i8_type().const_int(*k as u64, false),
-                    "",
-                );
-                self.set_current(value_table, counter, cur);
+            Op::MulLoop(step, targets, _) => {
+                self.build_mul_loop(function, env, value_table, counter, *step, targets);
             }
-            BfAST::SubOp(k) => {
-                let cur = self.builder.build_int_sub(
-                    cur,
-                    self.context.i8_type().const_int(*k as u64, false),
-                    "",
-                );
-                self.set_current(value_table, counter, cur);
+            Op::Clear(divisor, position) => {
+                self.build_clear(function, env, value_table, counter, *divisor, *position);
             }
-            BfAST::AddPtr(k) => {
-                let counter_v = self.builder.build_load(counter, "").into_int_value();
-                let counter_incr = self.builder.build_int_add(
-                    counter_v,
-                    self.context.i64_type().const_int(*k as u64, false),
-                    "",
-                );
-                self.builder.build_store(counter, counter_incr);
+            Op::Scan(stride, _) => {
+                self.build_scan(function, env, value_table, counter, *stride);
+            }
+            Op::Output(bytes, position) => {
+                self.build_output(function, env, bytes, *position);
+            }
+            Op::Tape(cells, _) => {
+                self.build_tape(function, env, value_table, counter, cells);
             }
-            BfAST::SubPtr(k) => {
+            Op::Add(offset, k, position) => {
+                self.build_count(env, COUNT_ADD, *k);
+                let cell_ref = self.get_cell_ref(value_table, counter, *offset);
+                let cell = self.builder.build_load(cell_ref, "").into_int_value();
+                let res = self.build_mul_add(function, env, cell, None, *k, false, *position);
+                self.builder.build_store(cell_ref, res);
+            }
+            Op::Sub(offset, k, position) => {
+                self.build_count(env, COUNT_SUB, *k);
+                let cell_ref = self.get_cell_ref(value_table, counter, *offset);
+                let cell = self.builder.build_load(cell_ref, "").into_int_value();
+                let res = self.build_mul_add(function, env, cell, None, *k, true, *position);
+                self.builder.build_store(cell_ref, res);
+            }
+            Op::Set(offset, value, _) => {
+                let cell_ref = self.get_cell_ref(value_table, counter, *offset);
+                self.builder
+                    .build_store(cell_ref, self.cell_type().const_int(*value, false));
+            }
+            Op::Move(offset, position) => {
+                let (index, n) = if *offset < 0 {
+                    (COUNT_LEFT, -*offset as u64)
+                } else {
+                    (COUNT_RIGHT, *offset as u64)
+                };
+                self.build_count(env, index, n);
+
                 let counter_v = self.builder.build_load(counter, "").into_int_value();
-                let counter_incr = self.builder.build_int_sub(
+                let counter_incr = self.builder.build_int_add(
                     counter_v,
-                    self.context.i64_type().const_int(*k as u64, false),
+                    self.context.i64_type().const_int(*offset as u64, true),
                     "",
                 );
+                self.build_bounds_check(function, env, counter_incr, *position);
                 self.builder.build_store(counter, counter_incr);
             }
-            BfAST::PutChar => {
-                self.builder.build_call(env.1, &[cur.into()], "");
+            Op::PutChar(offset, position) => {
+                self.build_count(env, COUNT_OUTPUT, 1);
+                let cell_ref = self.get_cell_ref(value_table, counter, *offset);
+                let cell = self.builder.build_load(cell_ref, "").into_int_value();
+                let c =
+                    self.builder
+                        .build_int_z_extend_or_bit_cast(cell, self.context.i64_type(), "");
+                self.builder
+                    .build_call(env.put_char, &[env.env.into(), c.into()], "");
+                self.build_io_check(function, env, *position);
             }
-            BfAST::GetChar => {
+            Op::GetChar(offset, position) => {
+                self.build_count(env, COUNT_INPUT, 1);
+                let cell_ref = self.get_cell_ref(value_table, counter, *offset);
+                let cell = self.builder.build_load(cell_ref, "").into_int_value();
+                let cur =
+                    self.builder
+                        .build_int_z_extend_or_bit_cast(cell, self.context.i64_type(), "");
                 let res = self
                     .builder
-                    .build_call(env.0, &[], "")
+                    .build_call(env.get_char, &[env.env.into(), cur.into()], "")
                     .try_as_basic_value()
                     .left()
                     .unwrap()
                     .into_int_value();
+                self.build_io_check(function, env, *position);
+                let res = self
+                    .builder
+                    .build_int_truncate_or_bit_cast(res, self.cell_type(), "");
 
-                self.set_current(value_table, counter, res);
+                self.builder.build_store(cell_ref, res);
             }
         }
 
         Ok(())
     }
 
+    /// Stops the program with an out-of-bounds error unless `counter` is on the tape
+    /// (checked mode only).
+    fn build_bounds_check(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        counter: IntValue<'c>,
+        position: Position,
+    ) {
+        if !self.options.checked {
+            return;
+        }
+
+        let in_bounds = self.context.append_basic_block(function, "");
+        let out_of_bounds = self.context.append_basic_block(function, "");
+
+        // a negative counter is out of bounds as an unsigned integer
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::ULT,
+                counter,
+                self.context
+                    .i64_type()
+                    .const_int(self.options.tape_size as u64, false),
+                "",
+            ),
+            in_bounds,
+            out_of_bounds,
+        );
+
+        self.builder.position_at_end(out_of_bounds);
+        self.build_runtime_error(env, ERROR_OUT_OF_BOUNDS, position, counter);
+
+        self.builder.position_at_end(in_bounds);
+    }
+
+    /// Stops the program with an I/O error if the last runtime call failed.
+    fn build_io_check(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        position: Position,
+    ) {
+        let br_okay = self.context.append_basic_block(function, "");
+        let br_failed = self.context.append_basic_block(function, "");
+
+        // the failure flag is the first byte of the environment
+        let failed = self.builder.build_load(env.env, "").into_int_value();
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::NE,
+                failed,
+                self.context.i8_type().const_int(0, false),
+                "",
+            ),
+            br_failed,
+            br_okay,
+        );
+
+        self.builder.position_at_end(br_failed);
+        self.build_runtime_error(
+            env,
+            ERROR_IO,
+            position,
+            self.context.i64_type().const_int(0, false),
+        );
+
+        self.builder.position_at_end(br_okay);
+    }
+
+    /// Stops the program with an underflow error unless `value` is a multiple of `step`.
+    fn build_step_check(
+        &self,
+        function: FunctionValue<'c>,
+        env: RuntimeEnv<'_, 'c>,
+        value: IntValue<'c>,
+        step: u64,
+        position: Position,
+    ) {
+        let cell_type = self.cell_type();
+
+        // a step which does not fit in a cell only divides zero
+        let remainder = if step > self.options.cell_width.max_value() {
+            value
+        } else {
+            self.builder
+                .build_int_unsigned_rem(value, cell_type.const_int(step, false), "")
+        };
+
+        let br_okay = self.context.append_basic_block(function, "");
+        let br_underflow = self.context.append_basic_block(function, "");
+
+        self.builder.build_conditional_branch(
+            self.builder.build_int_compare(
+                IntPredicate::EQ,
+                remainder,
+                cell_type.const_int(0, false),
+                "",
+            ),
+            br_okay,
+            br_underflow,
+        );
+
+        self.builder.position_at_end(br_underflow);
+        self.build_runtime_error(
+            env,
+            ERROR_UNDERFLOW,
+            position,
+            self.context.i64_type().const_int(0, false),
+        );
+
+        self.builder.position_at_end(br_okay);
+    }
+
+    /// Returns a pointer to the cell at `offset` from the current one.
+    fn get_cell_ref(
+        &self,
+        value_table: PointerValue<'c>,
+        counter: PointerValue<'c>,
+        offset: i64,
+    ) -> PointerValue<'c> {
+        let pos = self.builder.build_int_add(
+            self.builder.build_load(counter, "").into_int_value(),
+            self.context.i64_type().const_int(offset as u64, true),
+            "",
+        );
+
+        unsafe { self.builder.build_in_bounds_gep(value_table, &[pos], "") }
+    }
+
+    /// Reports a runtime error and returns from `bfrs_lang_start`.
+    fn build_runtime_error(
+        &self,
+        env: RuntimeEnv<'_, 'c>,
+        kind: u32,
+        position: Position,
+        value: IntValue<'c>,
+    ) {
+        self.builder.build_call(
+            env.runtime_error,
+            &[
+                env.env.into(),
+                self.context.i32_type().const_int(kind as u64, false).into(),
+                self.context
+                    .i64_type()
+                    .const_int(position.to_raw(), false)
+                    .into(),
+                value.into(),
+            ],
+            "",
+        );
+        self.build_return(env);
+    }
+
+    /// Returns the pointer from `bfrs_lang_start`.
+    fn build_return(&self, env: RuntimeEnv<'_, 'c>) {
+        let counter = self.builder.build_load(env.counter, "");
+        self.builder.build_return(Some(&counter));
+    }
+
+    /// Adds `n` to the count of instructions at `index` of [`InstructionCounts`]
+    /// (when counting instructions only).
+    fn build_count(&self, env: RuntimeEnv<'_, 'c>, index: u64, n: u64) {
+        if !self.options.count_instructions {
+            return;
+        }
+
+        let i64_type = self.context.i64_type();
+        let count_ref = unsafe {
+            self.builder
+                .build_in_bounds_gep(env.counts, &[i64_type.const_int(index, false)], "")
+        };
+        let count = self.builder.build_int_add(
+            self.builder.build_load(count_ref, "").into_int_value(),
+            i64_type.const_int(n, false),
+            "",
+        );
+        self.builder.build_store(count_ref, count);
+    }
+
     pub fn get_current(
         &self,
         value_table: PointerValue<'c>,
@@ -707,14 +1106,8 @@ impl<'c> Codegen<'c> {
     ) -> IntValue<'c> {
         let counter = self.builder.build_load(counter, "");
         let value = unsafe {
-            self.builder.build_in_bounds_gep(
-                value_table,
-                &[
-                    self.context.i64_type().const_int(0, false),
-                    counter.into_int_value(),
-                ],
-                "",
-            )
+            self.builder
+                .build_in_bounds_gep(value_table, &[counter.into_int_value()], "")
         };
 
         self.builder.build_load(value, "").into_int_value()
@@ -728,16 +1121,480 @@ impl<'c> Codegen<'c> {
     ) {
         let counter = self.builder.build_load(counter, "");
         let ref_val = unsafe {
-            self.builder.build_in_bounds_gep(
-                value_table,
-                &[
-                    self.context.i64_type().const_int(0, false),
-                    counter.into_int_value(),
-                ],
-                "",
-            )
+            self.builder
+                .build_in_bounds_gep(value_table, &[counter.into_int_value()], "")
         };
 
         self.builder.build_store(ref_val, value);
     }
 }
+
+impl<'c> CompiledProgram<'c> {
+    pub fn context(&self) -> &'c Context {
+        self.context
+    }
+
+    pub fn module(&self) -> &Module<'c> {
+        &self.module
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Name of the entry point to call on a blank tape.
+    pub(crate) fn entry_name(&self) -> &'static str {
+        if self.module.get_function("bfrs_lang_main").is_some() {
+            "bfrs_lang_main"
+        } else {
+            "bfrs_lang_start"
+        }
+    }
+
+    pub fn optimization_level(&self) -> OptimizationLevel {
+        optimization_level(&self.options)
+    }
+
+    /// Runs the LLVM optimization pipeline of the optimization level on the module.
+    ///
+    /// Has no effect once the program has been executed.
+    pub fn optimize(&self) {
+        self.options.log("optimizing...");
+
+        let pass_manager_builder = PassManagerBuilder::create();
+        pass_manager_builder.set_optimization_level(self.optimization_level());
+
+        let pass_manager = PassManager::create(());
+        pass_manager_builder.populate_module_pass_manager(&pass_manager);
+
+        pass_manager.run_on(&self.module);
+    }
+
+    /// Executes the program on the standard input and output.
+    pub fn execute(&self) -> Result<MachineState> {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+
+        self.execute_with_io(&mut stdin.lock(), &mut stdout.lock())
+    }
+
+    /// Executes the program, reading `,` from `input` and writing `.` to `output`.
+    pub fn execute_with_io(
+        &self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.execute_from(&[], 0, input, output)
+    }
+
+    /// Executes the program on a tape starting with the cells of `tape` and zero after,
+    /// with the pointer at `pointer`.
+    pub fn execute_from(
+        &self,
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.run_from(tape, pointer, input, output)
+            .map(FinalState::into_machine_state)
+    }
+
+    /// Executes the program like [`Self::execute_from`], without reading the final tape.
+    pub(crate) fn run_from(
+        &self,
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<FinalState> {
+        self.options.log("building...");
+        let start = Instant::now();
+
+        let name = if is_blank(tape, pointer) {
+            self.entry_name()
+        } else {
+            "bfrs_lang_start"
+        };
+        let entry: JitFunction<BfBootstrap> =
+            unsafe { self.execution_engine.get_function(name) }
+                .map_err(|_| Error::ice(format!("{} is not compiled", name)))?;
+
+        self.options.log(&format!("built in {:?}", start.elapsed()));
+
+        execute_compiled(
+            &self.options,
+            tape,
+            pointer,
+            input,
+            output,
+            |env, tape, pointer, counts| unsafe {
+                entry.call(
+                    env,
+                    bfrs_get_char,
+                    bfrs_print_char,
+                    bfrs_runtime_error,
+                    tape,
+                    pointer,
+                    counts,
+                )
+            },
+        )
+    }
+}
+
+fn optimization_level(options: &Options) -> OptimizationLevel {
+    if options.optimized {
+        OptimizationLevel::Aggressive
+    } else {
+        OptimizationLevel::None
+    }
+}
+
+#[test]
+fn test_execute_with_io() {
+    let ast = crate::parser::parse(",[.,]").unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::new(&ctx, false).unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    let mut output = vec![];
+    program
+        .execute_with_io(&mut &b"hello\x00"[..], &mut output)
+        .unwrap();
+
+    assert_eq!(output, b"hello");
+}
+
+#[test]
+fn test_execute_repeatedly() {
+    let ctx = Context::create();
+    let codegen = Codegen::new(&ctx, true).unwrap();
+
+    let echo = codegen
+        .compile(&crate::parser::parse(",[.,]").unwrap())
+        .unwrap();
+    let hello = codegen
+        .compile(&crate::parser::parse("++++++++[>+++++++++<-]>.").unwrap())
+        .unwrap();
+    echo.optimize();
+
+    for &input in &["foo", "bar"] {
+        let mut output = vec![];
+        echo.execute_with_io(&mut format!("{}\0", input).as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, input.as_bytes());
+
+        let mut output = vec![];
+        hello.execute_with_io(&mut &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"H");
+    }
+}
+
+#[test]
+fn test_large_tape() {
+    // prints 'A' on the 100001st cell
+    let program = format!("{}++++++++[<++++++++>-]<+.", ">".repeat(100_001));
+    let ast = crate::parser::parse(program).unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::with_options(
+        &ctx,
+        Options {
+            tape_size: 1 << 20,
+            // runs the program rather than its output computed at compile time
+            prefix_steps: 0,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    let mut output = vec![];
+    program.execute_with_io(&mut &b""[..], &mut output).unwrap();
+
+    assert_eq!(output, b"A");
+}
+
+#[test]
+fn test_checked_out_of_bounds() {
+    use crate::runtime::RuntimeError;
+
+    let ast = crate::parser::parse("+[>+]").unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::with_options(
+        &ctx,
+        Options {
+            tape_size: 16,
+            checked: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    match program.execute_with_io(&mut &b""[..], &mut vec![]) {
+        Err(Error::Runtime(RuntimeError::OutOfBounds { position, pointer })) => {
+            assert_eq!(position, Position { line: 1, column: 3 });
+            assert_eq!(pointer, 16);
+        }
+        res => panic!("expected out-of-bounds error, but got {:?}", res),
+    }
+}
+
+#[test]
+fn test_cell_width() {
+    use crate::options::CellWidth;
+
+    // the loop is only entered if 256 does not wrap around to 0
+    let program = format!("{}[>{}<[-]]>.", "+".repeat(256), "+".repeat(33));
+    let ast = crate::parser::parse(program).unwrap();
+
+    for &(cell_width, expected) in &[
+        (CellWidth::U8, b"\0"),
+        (CellWidth::U16, b"!"),
+        (CellWidth::U32, b"!"),
+        (CellWidth::U64, b"!"),
+    ] {
+        let ctx = Context::create();
+        let codegen = Codegen::with_options(
+            &ctx,
+            Options {
+                cell_width,
+                prefix_steps: 0,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let program = codegen.compile(&ast).unwrap();
+
+        let mut output = vec![];
+        program.execute_with_io(&mut &b""[..], &mut output).unwrap();
+
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn test_overflow() {
+    use crate::options::Overflow;
+    use crate::runtime::RuntimeError;
+
+    let execute = |program: &str, overflow: Overflow| {
+        let ast = crate::parser::parse(program).unwrap();
+
+        let ctx = Context::create();
+        let codegen = Codegen::with_options(
+            &ctx,
+            Options {
+                overflow,
+                prefix_steps: 0,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let program = codegen.compile(&ast).unwrap();
+
+        let mut output = vec![];
+        program
+            .execute_with_io(&mut &b""[..], &mut output)
+            .map(|_| output)
+    };
+
+    // 16 * 20 = 320 by a multiplication loop
+    let mul = format!("{}[>{}<-]>.", "+".repeat(16), "+".repeat(20));
+
+    assert_eq!(execute("-.", Overflow::Wrap).unwrap(), b"\xFF");
+    assert_eq!(execute(&mul, Overflow::Wrap).unwrap(), b"\x40");
+
+    assert_eq!(execute("-.", Overflow::Saturate).unwrap(), b"\0");
+    assert_eq!(execute(&mul, Overflow::Saturate).unwrap(), b"\xFF");
+
+    match execute("-.", Overflow::Trap) {
+        Err(Error::Runtime(RuntimeError::Underflow { position })) => {
+            assert_eq!(position, Position { line: 1, column: 1 });
+        }
+        res => panic!("expected underflow, but got {:?}", res),
+    }
+
+    match execute(&mul, Overflow::Trap) {
+        Err(Error::Runtime(RuntimeError::Overflow { position })) => {
+            assert_eq!(
+                position,
+                Position {
+                    line: 1,
+                    column: 19
+                }
+            );
+        }
+        res => panic!("expected overflow, but got {:?}", res),
+    }
+}
+
+#[test]
+fn test_eof() {
+    use crate::options::Eof;
+
+    let ast = crate::parser::parse("+++,.").unwrap();
+
+    for &(eof, expected) in &[
+        (Eof::Zero, b"\0"),
+        (Eof::Max, b"\xFF"),
+        (Eof::Unchanged, b"\x03"),
+    ] {
+        let ctx = Context::create();
+        let codegen = Codegen::with_options(
+            &ctx,
+            Options {
+                eof,
+                prefix_steps: 0,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        let program = codegen.compile(&ast).unwrap();
+
+        let mut output = vec![];
+        program.execute_with_io(&mut &b""[..], &mut output).unwrap();
+
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn test_input_error() {
+    use crate::runtime::RuntimeError;
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    let ast = crate::parser::parse(".\n,[.,]").unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::new(&ctx, false).unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    let mut output = vec![];
+    match program.execute_with_io(&mut Failing, &mut output) {
+        Err(Error::Runtime(RuntimeError::Io { position, source })) => {
+            assert_eq!(position, Position { line: 2, column: 1 });
+            assert_eq!(source.kind(), std::io::ErrorKind::BrokenPipe);
+        }
+        res => panic!("expected I/O error, but got {:?}", res),
+    }
+
+    // the output is flushed before reading
+    assert_eq!(output, b"\0");
+}
+
+#[test]
+fn test_output_error() {
+    use crate::options::Buffering;
+    use crate::runtime::RuntimeError;
+
+    struct Failing;
+
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let ast = crate::parser::parse("+[.]").unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::with_options(
+        &ctx,
+        Options {
+            buffering: Buffering::None,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    match program.execute_with_io(&mut &b""[..], &mut Failing) {
+        Err(Error::Runtime(RuntimeError::Io { position, .. })) => {
+            assert_eq!(position, Position { line: 1, column: 3 });
+        }
+        res => panic!("expected I/O error, but got {:?}", res),
+    }
+}
+
+#[test]
+fn test_machine_state() {
+    use crate::runtime::InstructionCounts;
+
+    let ast = crate::parser::parse("+[->++<]>>+<").unwrap();
+
+    let ctx = Context::create();
+    let codegen = Codegen::with_options(
+        &ctx,
+        Options {
+            count_instructions: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let program = codegen.compile(&ast).unwrap();
+
+    let state = program.execute_with_io(&mut &b""[..], &mut vec![]).unwrap();
+    assert_eq!(state.tape, vec![0, 2, 1]);
+    assert_eq!(state.pointer, 1);
+    assert_eq!(
+        state.counts,
+        Some(InstructionCounts {
+            add: 4,
+            sub: 1,
+            right: 3,
+            left: 2,
+            output: 0,
+            input: 0,
+            loop_start: 1,
+            loop_end: 1,
+        })
+    );
+
+    let state = program
+        .execute_from(&[3, 0, 5], 0, &mut &b""[..], &mut vec![])
+        .unwrap();
+    assert_eq!(state.tape, vec![0, 6, 6]);
+    assert_eq!(state.counts.unwrap().loop_end, 3);
+}
+
+#[cfg(test)]
+fn compile_and_execute(
+    ast: &[BfAST],
+    options: &Options,
+    tape: &[u64],
+    pointer: usize,
+) -> MachineState {
+    let ctx = Context::create();
+    let codegen = Codegen::with_options(&ctx, options.clone()).unwrap();
+    codegen
+        .compile(ast)
+        .unwrap()
+        .execute_from(tape, pointer, &mut &b""[..], &mut vec![])
+        .unwrap()
+}
+
+#[test]
+fn test_division_loops() {
+    crate::interpreter::check_division_loops(compile_and_execute);
+}
+
+#[test]
+fn test_scans() {
+    crate::interpreter::check_scans(compile_and_execute);
+}
@@ -0,0 +1,977 @@
+//! JIT compilation with Cranelift, compiling faster than LLVM at the cost of throughput.
+use crate::ir::{self, MulTarget, Op};
+use crate::optimizer;
+use crate::options::{CellWidth, Options, Overflow};
+use crate::parser::{BfAST, Position};
+use crate::runtime::{
+    bfrs_get_char, bfrs_print_char, bfrs_runtime_error, bfrs_scan_back, execute_compiled, is_blank,
+    BfBootstrap, FinalState, MachineState, COUNT_ADD, COUNT_INPUT, COUNT_LEFT, COUNT_LOOP_END,
+    COUNT_LOOP_START, COUNT_OUTPUT, COUNT_RIGHT, COUNT_SUB, ERROR_IO, ERROR_OUT_OF_BOUNDS,
+    ERROR_OVERFLOW, ERROR_UNDERFLOW,
+};
+use crate::{Error, Result};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, FuncRef, InstBuilder, MemFlags, SigRef, Signature, Type, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, DataDescription, FuncId, Linkage, Module};
+
+use std::io::{Read, Write};
+use std::time::Instant;
+
+/// Compiler of programs into native code with Cranelift.
+pub struct CraneliftCodegen {
+    options: Options,
+}
+
+/// A program compiled by [`CraneliftCodegen::compile`], which can be executed any number
+/// of times.
+pub struct CraneliftProgram {
+    // owns the memory of the code
+    module: Option<JITModule>,
+    entry: BfBootstrap,
+    /// entry point for a blank tape, if it differs
+    blank_entry: Option<BfBootstrap>,
+    options: Options,
+}
+
+/// Builder of the body of an entry point.
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    options: &'a Options,
+    cell_type: Type,
+    pointer: Variable,
+    env: Value,
+    get_char: Value,
+    put_char: Value,
+    runtime_error: Value,
+    tape: Value,
+    counts: Value,
+    get_char_sig: SigRef,
+    put_char_sig: SigRef,
+    runtime_error_sig: SigRef,
+    memchr: FuncRef,
+    scan_back: FuncRef,
+}
+
+fn cranelift_error(e: impl std::fmt::Display) -> Error {
+    Error::Cranelift(e.to_string())
+}
+
+impl CraneliftCodegen {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Compiles the program into `bfrs_lang_start`, with the same ABI as [`Codegen`].
+    ///
+    /// The program is also compiled into `bfrs_lang_main` for a blank tape, if that removes
+    /// anything.
+    ///
+    /// [`Codegen`]: crate::codegen::Codegen
+    pub fn compile(&self, ast: &[BfAST]) -> Result<CraneliftProgram> {
+        self.options.log("compiling...");
+        let start = Instant::now();
+
+        let mut flags = settings::builder();
+        flags
+            .set(
+                "opt_level",
+                if self.options.optimized {
+                    "speed"
+                } else {
+                    "none"
+                },
+            )
+            .map_err(cranelift_error)?;
+        let isa = cranelift_native::builder()
+            .map_err(cranelift_error)?
+            .finish(settings::Flags::new(flags))
+            .map_err(cranelift_error)?;
+
+        let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+        jit_builder.symbol("bfrs_scan_back", bfrs_scan_back as *const u8);
+        let mut module = JITModule::new(jit_builder);
+        let ptr_type = module.target_config().pointer_type();
+        let call_conv = module.target_config().default_call_conv;
+
+        // scans of byte cells search the tape with memchr of the C library
+        let mut memchr_sig = Signature::new(call_conv);
+        memchr_sig.params.push(AbiParam::new(ptr_type));
+        memchr_sig.params.push(AbiParam::new(types::I32));
+        memchr_sig.params.push(AbiParam::new(ptr_type));
+        memchr_sig.returns.push(AbiParam::new(ptr_type));
+        let memchr = module
+            .declare_function("memchr", Linkage::Import, &memchr_sig)
+            .map_err(cranelift_error)?;
+
+        // and backward scans with the runtime
+        let mut scan_back_sig = Signature::new(call_conv);
+        scan_back_sig.params.push(AbiParam::new(ptr_type));
+        scan_back_sig.params.push(AbiParam::new(types::I64));
+        scan_back_sig.returns.push(AbiParam::new(types::I64));
+        let scan_back = module
+            .declare_function("bfrs_scan_back", Linkage::Import, &scan_back_sig)
+            .map_err(cranelift_error)?;
+
+        let ops = optimizer::optimize(ir::lower(ast, &self.options), &self.options);
+        let blank_ops = optimizer::specialize_blank(ast, &self.options);
+
+        let id = self.define_entry(&mut module, "bfrs_lang_start", &ops, memchr, scan_back)?;
+        // programs failing to compile for a blank tape still start from bfrs_lang_start
+        let blank_id = if blank_ops != ops {
+            match self.define_entry(&mut module, "bfrs_lang_main", &blank_ops, memchr, scan_back) {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    self.options
+                        .log(&format!("not specialized for a blank tape: {}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        module.finalize_definitions().map_err(cranelift_error)?;
+
+        let get_entry = |id| unsafe {
+            std::mem::transmute::<*const u8, BfBootstrap>(module.get_finalized_function(id))
+        };
+        let entry = get_entry(id);
+        let blank_entry = blank_id.map(get_entry);
+
+        self.options
+            .log(&format!("compiled in {:?}", start.elapsed()));
+
+        Ok(CraneliftProgram {
+            module: Some(module),
+            entry,
+            blank_entry,
+            options: self.options.clone(),
+        })
+    }
+
+    /// Defines an entry point named `name` running the operations.
+    fn define_entry(
+        &self,
+        module: &mut JITModule,
+        name: &str,
+        ops: &[Op],
+        memchr: FuncId,
+        scan_back: FuncId,
+    ) -> Result<FuncId> {
+        let ptr_type = module.target_config().pointer_type();
+        let call_conv = module.target_config().default_call_conv;
+
+        let mut get_char_sig = Signature::new(call_conv);
+        get_char_sig.params.push(AbiParam::new(ptr_type));
+        get_char_sig.params.push(AbiParam::new(types::I64));
+        get_char_sig.returns.push(AbiParam::new(types::I64));
+
+        let mut put_char_sig = Signature::new(call_conv);
+        put_char_sig.params.push(AbiParam::new(ptr_type));
+        put_char_sig.params.push(AbiParam::new(types::I64));
+
+        let mut runtime_error_sig = Signature::new(call_conv);
+        runtime_error_sig.params.push(AbiParam::new(ptr_type));
+        runtime_error_sig.params.push(AbiParam::new(types::I32));
+        runtime_error_sig.params.push(AbiParam::new(types::I64));
+        runtime_error_sig.params.push(AbiParam::new(types::I64));
+
+        let mut ctx = module.make_context();
+        let signature = &mut ctx.func.signature;
+        // env, get_char, put_char, runtime_error, tape
+        for _ in 0..5 {
+            signature.params.push(AbiParam::new(ptr_type));
+        }
+        signature.params.push(AbiParam::new(types::I64));
+        signature.params.push(AbiParam::new(ptr_type));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        let params = builder.block_params(entry_block).to_vec();
+
+        let pointer = Variable::from_u32(0);
+        builder.declare_var(pointer, types::I64);
+        builder.def_var(pointer, params[5]);
+
+        let mut translator = Translator {
+            get_char_sig: builder.import_signature(get_char_sig),
+            put_char_sig: builder.import_signature(put_char_sig),
+            runtime_error_sig: builder.import_signature(runtime_error_sig),
+            memchr: module.declare_func_in_func(memchr, builder.func),
+            scan_back: module.declare_func_in_func(scan_back, builder.func),
+            builder,
+            module,
+            options: &self.options,
+            cell_type: Type::int(self.options.cell_width.bits() as u16).unwrap(),
+            pointer,
+            env: params[0],
+            get_char: params[1],
+            put_char: params[2],
+            runtime_error: params[3],
+            tape: params[4],
+            counts: params[6],
+        };
+
+        translator.translate(ops)?;
+        translator.build_return();
+        translator.builder.seal_all_blocks();
+        translator.builder.finalize();
+
+        let id = module
+            .declare_function(name, Linkage::Export, &ctx.func.signature)
+            .map_err(cranelift_error)?;
+        module
+            .define_function(id, &mut ctx)
+            .map_err(cranelift_error)?;
+        module.clear_context(&mut ctx);
+
+        Ok(id)
+    }
+}
+
+impl CraneliftProgram {
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Executes the program on the standard input and output.
+    pub fn execute(&self) -> Result<MachineState> {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+
+        self.execute_with_io(&mut stdin.lock(), &mut stdout.lock())
+    }
+
+    /// Executes the program, reading `,` from `input` and writing `.` to `output`.
+    pub fn execute_with_io(
+        &self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.execute_from(&[], 0, input, output)
+    }
+
+    /// Executes the program on a tape starting with the cells of `tape` and zero after,
+    /// with the pointer at `pointer`.
+    pub fn execute_from(
+        &self,
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.run_from(tape, pointer, input, output)
+            .map(FinalState::into_machine_state)
+    }
+
+    /// Executes the program like [`Self::execute_from`], without reading the final tape.
+    pub(crate) fn run_from(
+        &self,
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<FinalState> {
+        let entry = match self.blank_entry {
+            Some(blank_entry) if is_blank(tape, pointer) => blank_entry,
+            _ => self.entry,
+        };
+
+        execute_compiled(
+            &self.options,
+            tape,
+            pointer,
+            input,
+            output,
+            |env, tape, pointer, counts| unsafe {
+                entry(
+                    env,
+                    bfrs_get_char,
+                    bfrs_print_char,
+                    bfrs_runtime_error,
+                    tape,
+                    pointer,
+                    counts,
+                )
+            },
+        )
+    }
+}
+
+impl Drop for CraneliftProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // the entry point is not reachable anymore
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+impl Translator<'_> {
+    fn translate(&mut self, ops: &[Op]) -> Result<()> {
+        for op in ops {
+            match op {
+                Op::Loop(v, _) | Op::EnteredLoop(v, _) => {
+                    self.build_count(COUNT_LOOP_START, 1);
+
+                    let loop_head = self.builder.create_block();
+                    let loop_body = self.builder.create_block();
+                    let loop_end = self.builder.create_block();
+
+                    // an entered loop skips the first check
+                    let first = match op {
+                        Op::EnteredLoop(..) => loop_body,
+                        _ => loop_head,
+                    };
+                    self.builder.ins().jump(first, &[]);
+
+                    self.builder.switch_to_block(loop_head);
+                    let cur = self.load_cell(0);
+                    self.builder.ins().brif(cur, loop_body, &[], loop_end, &[]);
+
+                    self.builder.switch_to_block(loop_body);
+                    self.translate(v)?;
+                    self.build_count(COUNT_LOOP_END, 1);
+                    self.builder.ins().jump(loop_head, &[]);
+
+                    self.builder.switch_to_block(loop_end);
+                }
+                Op::MulLoop(step, targets, _) => self.build_mul_loop(*step, targets),
+                Op::Clear(divisor, position) => self.build_clear(*divisor, *position),
+                Op::Scan(stride, _) => self.build_scan(*stride),
+                Op::Output(bytes, position) => self.build_output(bytes, *position)?,
+                Op::Tape(cells, _) => self.build_tape(cells)?,
+                Op::Add(offset, k, position) => {
+                    self.build_count(COUNT_ADD, *k);
+                    self.build_add(*offset, *k, false, *position);
+                }
+                Op::Sub(offset, k, position) => {
+                    self.build_count(COUNT_SUB, *k);
+                    self.build_add(*offset, *k, true, *position);
+                }
+                Op::Set(offset, value, _) => {
+                    let value = self.builder.ins().iconst(self.cell_type, *value as i64);
+                    self.store_cell(*offset, value);
+                }
+                Op::Move(offset, position) => {
+                    let (index, n) = if *offset < 0 {
+                        (COUNT_LEFT, -*offset as u64)
+                    } else {
+                        (COUNT_RIGHT, *offset as u64)
+                    };
+                    self.build_count(index, n);
+                    self.build_move(*offset, *position);
+                }
+                Op::PutChar(offset, position) => {
+                    self.build_count(COUNT_OUTPUT, 1);
+
+                    let c = self.load_cell(*offset);
+                    let c = self.extend(c);
+                    self.builder.ins().call_indirect(
+                        self.put_char_sig,
+                        self.put_char,
+                        &[self.env, c],
+                    );
+                    self.build_io_check(*position);
+                }
+                Op::GetChar(offset, position) => {
+                    self.build_count(COUNT_INPUT, 1);
+
+                    let cur = self.load_cell(*offset);
+                    let cur = self.extend(cur);
+                    let call = self.builder.ins().call_indirect(
+                        self.get_char_sig,
+                        self.get_char,
+                        &[self.env, cur],
+                    );
+                    let res = self.builder.inst_results(call)[0];
+                    self.build_io_check(*position);
+
+                    let res = if self.cell_type == types::I64 {
+                        res
+                    } else {
+                        self.builder.ins().ireduce(self.cell_type, res)
+                    };
+                    self.store_cell(*offset, res);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds or subtracts `k` to the cell at `offset` with the overflow semantics.
+    fn build_add(&mut self, offset: i64, k: u64, subtract: bool, position: Position) {
+        let cur = self.load_cell(offset);
+        let res = self.build_mul_add(cur, None, k, subtract, position);
+        self.store_cell(offset, res);
+    }
+
+    /// Adds the number of iterations of the loop stepping the current cell by `step` into
+    /// the targets and clears it, if it is not zero.
+    fn build_mul_loop(&mut self, step: u64, targets: &[MulTarget]) {
+        let entered = self.builder.create_block();
+        let end = self.builder.create_block();
+
+        let cur = self.load_cell(0);
+        self.builder.ins().brif(cur, entered, &[], end, &[]);
+
+        self.builder.switch_to_block(entered);
+        let iterations = if step == 1 {
+            cur
+        } else {
+            self.build_iterations(cur, step)
+        };
+
+        for target in targets {
+            let dest = self.load_cell(target.offset);
+            let res = self.build_mul_add(
+                dest,
+                Some(iterations),
+                target.factor,
+                target.subtract,
+                target.position,
+            );
+            self.store_cell(target.offset, res);
+        }
+
+        let zero = self.builder.ins().iconst(self.cell_type, 0);
+        self.store_cell(0, zero);
+        self.builder.ins().jump(end, &[]);
+
+        self.builder.switch_to_block(end);
+    }
+
+    /// Computes the number of iterations of a loop subtracting `step` from the non-zero `value`
+    /// until it is zero.
+    fn build_iterations(&mut self, value: Value, step: u64) -> Value {
+        let max = self.options.cell_width.max_value();
+
+        if self.options.overflow == Overflow::Wrap {
+            // value = step * n modulo the cell size, where step = odd << shift
+            let shift = step.trailing_zeros();
+            let odd = step >> shift;
+
+            if shift != 0 {
+                let remainder = self.builder.ins().band_imm(value, (1 << shift) - 1);
+                self.build_forever_if(remainder);
+            }
+
+            let inverse = optimizer::modular_inverse(odd) & max;
+            let value = self.builder.ins().ushr_imm(value, i64::from(shift));
+            let n = self.builder.ins().imul_imm(value, inverse as i64);
+            return self.builder.ins().band_imm(n, (max >> shift) as i64);
+        }
+
+        // the cell saturates at zero in the first iteration
+        if step > max {
+            return self.builder.ins().iconst(self.cell_type, 1);
+        }
+
+        // rounds up, as the last iteration saturates
+        let quotient = self.builder.ins().udiv_imm(value, step as i64);
+        let remainder = self.builder.ins().urem_imm(value, step as i64);
+        let zero = self.builder.ins().iconst(self.cell_type, 0);
+        let one = self.builder.ins().iconst(self.cell_type, 1);
+        let rounded = self.builder.ins().select(remainder, one, zero);
+        self.builder.ins().iadd(quotient, rounded)
+    }
+
+    /// Runs forever if `condition` is not zero, like a loop never reaching zero.
+    fn build_forever_if(&mut self, condition: Value) {
+        let okay = self.builder.create_block();
+        let forever = self.builder.create_block();
+        self.builder.ins().brif(condition, forever, &[], okay, &[]);
+
+        self.builder.switch_to_block(forever);
+        self.builder.ins().jump(forever, &[]);
+
+        self.builder.switch_to_block(okay);
+    }
+
+    /// Clears the current cell, if the clear loop terminates on it.
+    fn build_clear(&mut self, divisor: u64, position: Position) {
+        if divisor == 0 {
+            // the loop never steps the cell, so only terminates on zero
+            let cur = self.load_cell(0);
+            self.build_forever_if(cur);
+        } else if divisor != 1 {
+            let cur = self.load_cell(0);
+
+            if self.options.overflow == Overflow::Trap {
+                // underflows unless the cell is a multiple of the step
+                let remainder = if divisor > self.options.cell_width.max_value() {
+                    cur
+                } else {
+                    self.builder.ins().urem_imm(cur, divisor as i64)
+                };
+                let zero = self.builder.ins().iconst(types::I64, 0);
+                self.build_error_if(remainder, ERROR_UNDERFLOW, position, zero);
+            } else {
+                // the divisor is a power of two with wrapping
+                let remainder = self.builder.ins().band_imm(cur, divisor as i64 - 1);
+                self.build_forever_if(remainder);
+            }
+        }
+
+        let zero = self.builder.ins().iconst(self.cell_type, 0);
+        self.store_cell(0, zero);
+    }
+
+    /// Writes the bytes from a constant in the module.
+    fn build_output(&mut self, bytes: &[u8], position: Position) -> Result<()> {
+        let mut data = DataDescription::new();
+        data.define(bytes.to_vec().into_boxed_slice());
+        let id = self
+            .module
+            .declare_anonymous_data(false, false)
+            .map_err(cranelift_error)?;
+        self.module
+            .define_data(id, &data)
+            .map_err(cranelift_error)?;
+
+        let ptr_type = self.module.target_config().pointer_type();
+        let global = self.module.declare_data_in_func(id, self.builder.func);
+        let start = self.builder.ins().global_value(ptr_type, global);
+
+        let head = self.builder.create_block();
+        let end = self.builder.create_block();
+        self.builder.append_block_param(head, types::I64);
+
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.builder.ins().jump(head, &[zero]);
+
+        // the bytes are not empty
+        self.builder.switch_to_block(head);
+        let index = self.builder.block_params(head)[0];
+        let byte_ref = self.builder.ins().iadd(start, index);
+        let c = self
+            .builder
+            .ins()
+            .uload8(types::I64, MemFlags::trusted(), byte_ref, 0);
+        self.builder
+            .ins()
+            .call_indirect(self.put_char_sig, self.put_char, &[self.env, c]);
+        self.build_io_check(position);
+
+        let next = self.builder.ins().iadd_imm(index, 1);
+        let more = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedLessThan, next, bytes.len() as i64);
+        self.builder.ins().brif(more, head, &[next], end, &[]);
+
+        self.builder.switch_to_block(end);
+
+        Ok(())
+    }
+
+    /// Stores the cells from the current one on from a constant in the module.
+    fn build_tape(&mut self, cells: &[u64]) -> Result<()> {
+        let bytes: Vec<u8> = cells
+            .iter()
+            .flat_map(|&cell| match self.options.cell_width {
+                CellWidth::U8 => (cell as u8).to_ne_bytes().to_vec(),
+                CellWidth::U16 => (cell as u16).to_ne_bytes().to_vec(),
+                CellWidth::U32 => (cell as u32).to_ne_bytes().to_vec(),
+                CellWidth::U64 => cell.to_ne_bytes().to_vec(),
+            })
+            .collect();
+        let len = bytes.len();
+
+        let mut data = DataDescription::new();
+        data.define(bytes.into_boxed_slice());
+        let id = self
+            .module
+            .declare_anonymous_data(false, false)
+            .map_err(cranelift_error)?;
+        self.module
+            .define_data(id, &data)
+            .map_err(cranelift_error)?;
+
+        let config = self.module.target_config();
+        let global = self.module.declare_data_in_func(id, self.builder.func);
+        let src = self
+            .builder
+            .ins()
+            .global_value(config.pointer_type(), global);
+        let dest = self.cell_ref(0);
+        let size = self.builder.ins().iconst(config.pointer_type(), len as i64);
+        self.builder.call_memcpy(config, dest, src, size);
+
+        Ok(())
+    }
+
+    /// Moves the pointer by `stride` until the current cell is zero, searching the tape
+    /// with `memchr` for forward scans of byte cells, and with the runtime for backward ones.
+    /// Other scans loop over the cells.
+    fn build_scan(&mut self, stride: i64) {
+        let pointer = self.builder.use_var(self.pointer);
+
+        if stride == 1 && self.options.cell_width == CellWidth::U8 {
+            let tape_size = self
+                .builder
+                .ins()
+                .iconst(types::I64, self.options.tape_size as i64);
+            let start = self.builder.ins().iadd(self.tape, pointer);
+            let len = self.builder.ins().isub(tape_size, pointer);
+            let zero = self.builder.ins().iconst(types::I32, 0);
+
+            let call = self.builder.ins().call(self.memchr, &[start, zero, len]);
+            let found = self.builder.inst_results(call)[0];
+
+            // runs off the end of the tape without a zero
+            let index = self.builder.ins().isub(found, self.tape);
+            let pointer = self.builder.ins().select(found, index, tape_size);
+            self.builder.def_var(self.pointer, pointer);
+
+            return;
+        }
+
+        if stride == -1 && self.options.cell_width == CellWidth::U8 {
+            let call = self
+                .builder
+                .ins()
+                .call(self.scan_back, &[self.tape, pointer]);
+            let pointer = self.builder.inst_results(call)[0];
+            self.builder.def_var(self.pointer, pointer);
+
+            return;
+        }
+
+        let head = self.builder.create_block();
+        let step = self.builder.create_block();
+        let end = self.builder.create_block();
+        self.builder.append_block_param(head, types::I64);
+
+        self.builder.ins().jump(head, &[pointer]);
+
+        self.builder.switch_to_block(head);
+        let pointer = self.builder.block_params(head)[0];
+        self.builder.def_var(self.pointer, pointer);
+        let cur = self.load_cell(0);
+        self.builder.ins().brif(cur, step, &[], end, &[]);
+
+        self.builder.switch_to_block(step);
+        let next = self.builder.ins().iadd_imm(pointer, stride);
+        self.builder.ins().jump(head, &[next]);
+
+        self.builder.switch_to_block(end);
+    }
+
+    /// Computes `value + factor * count` (or `value - factor * count` if `subtract`), where
+    /// `count` defaults to 1 and is never zero, under the overflow semantics of the options.
+    fn build_mul_add(
+        &mut self,
+        value: Value,
+        count: Option<Value>,
+        factor: u64,
+        subtract: bool,
+        position: Position,
+    ) -> Value {
+        let max = self.options.cell_width.max_value();
+        let bound = if subtract { 0 } else { max as i64 };
+
+        if self.options.overflow == Overflow::Wrap {
+            let factor = self
+                .builder
+                .ins()
+                .iconst(self.cell_type, (factor & max) as i64);
+            let delta = match count {
+                Some(count) => self.builder.ins().imul(count, factor),
+                None => factor,
+            };
+
+            return if subtract {
+                self.builder.ins().isub(value, delta)
+            } else {
+                self.builder.ins().iadd(value, delta)
+            };
+        }
+
+        // a cell cannot hold the delta, so the cell always over/underflows
+        if factor > max {
+            if self.options.overflow == Overflow::Trap {
+                let always = self.builder.ins().iconst(types::I8, 1);
+                self.build_overflow_check(always, subtract, position);
+            }
+
+            return self.builder.ins().iconst(self.cell_type, bound);
+        }
+
+        let factor = self.builder.ins().iconst(self.cell_type, factor as i64);
+        let (delta, delta_overflow) = match count {
+            Some(count) => {
+                let (delta, overflow) = self.builder.ins().umul_overflow(count, factor);
+                (delta, Some(overflow))
+            }
+            None => (factor, None),
+        };
+
+        let (res, overflow) = if subtract {
+            self.builder.ins().usub_overflow(value, delta)
+        } else {
+            self.builder.ins().uadd_overflow(value, delta)
+        };
+        let overflow = match delta_overflow {
+            Some(delta_overflow) => self.builder.ins().bor(overflow, delta_overflow),
+            None => overflow,
+        };
+
+        if self.options.overflow == Overflow::Trap {
+            self.build_overflow_check(overflow, subtract, position);
+            res
+        } else {
+            let bound = self.builder.ins().iconst(self.cell_type, bound);
+            self.builder.ins().select(overflow, bound, res)
+        }
+    }
+
+    /// Moves the pointer by `offset`, checking the bounds in checked mode.
+    fn build_move(&mut self, offset: i64, position: Position) {
+        let pointer = self.builder.use_var(self.pointer);
+        let pointer = self.builder.ins().iadd_imm(pointer, offset);
+
+        if self.options.checked {
+            // a negative pointer is out of bounds as an unsigned integer
+            let out_of_bounds = self.builder.ins().icmp_imm(
+                IntCC::UnsignedGreaterThanOrEqual,
+                pointer,
+                self.options.tape_size as i64,
+            );
+            self.build_error_if(out_of_bounds, ERROR_OUT_OF_BOUNDS, position, pointer);
+        }
+
+        self.builder.def_var(self.pointer, pointer);
+    }
+
+    fn build_overflow_check(&mut self, overflowed: Value, subtract: bool, position: Position) {
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.build_error_if(
+            overflowed,
+            if subtract {
+                ERROR_UNDERFLOW
+            } else {
+                ERROR_OVERFLOW
+            },
+            position,
+            zero,
+        );
+    }
+
+    /// Stops the program with an I/O error if the last runtime call failed.
+    fn build_io_check(&mut self, position: Position) {
+        // the failure flag is the first byte of the environment
+        let failed = self
+            .builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), self.env, 0);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.build_error_if(failed, ERROR_IO, position, zero);
+    }
+
+    /// Reports a runtime error and returns from `bfrs_lang_start` if `condition` is not zero.
+    fn build_error_if(&mut self, condition: Value, kind: u32, position: Position, value: Value) {
+        let error = self.builder.create_block();
+        let okay = self.builder.create_block();
+        self.builder.ins().brif(condition, error, &[], okay, &[]);
+
+        self.builder.switch_to_block(error);
+        let kind = self.builder.ins().iconst(types::I32, i64::from(kind));
+        let position = self
+            .builder
+            .ins()
+            .iconst(types::I64, position.to_raw() as i64);
+        self.builder.ins().call_indirect(
+            self.runtime_error_sig,
+            self.runtime_error,
+            &[self.env, kind, position, value],
+        );
+        self.build_return();
+
+        self.builder.switch_to_block(okay);
+    }
+
+    /// Returns the pointer from `bfrs_lang_start`.
+    fn build_return(&mut self) {
+        let pointer = self.builder.use_var(self.pointer);
+        self.builder.ins().return_(&[pointer]);
+    }
+
+    /// Adds `n` to the count of instructions at `index` (when counting instructions only).
+    fn build_count(&mut self, index: u64, n: u64) {
+        if !self.options.count_instructions {
+            return;
+        }
+
+        let offset = (index * 8) as i32;
+        let count = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), self.counts, offset);
+        let count = self.builder.ins().iadd_imm(count, n as i64);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), count, self.counts, offset);
+    }
+
+    /// Returns the address of the cell at `offset` from the current one.
+    fn cell_ref(&mut self, offset: i64) -> Value {
+        let pointer = self.builder.use_var(self.pointer);
+        let pointer = self.builder.ins().iadd_imm(pointer, offset);
+        let offset = self
+            .builder
+            .ins()
+            .imul_imm(pointer, self.options.cell_width.bytes() as i64);
+        self.builder.ins().iadd(self.tape, offset)
+    }
+
+    fn load_cell(&mut self, offset: i64) -> Value {
+        let cell_ref = self.cell_ref(offset);
+        self.builder
+            .ins()
+            .load(self.cell_type, MemFlags::trusted(), cell_ref, 0)
+    }
+
+    fn store_cell(&mut self, offset: i64, value: Value) {
+        let cell_ref = self.cell_ref(offset);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), value, cell_ref, 0);
+    }
+
+    /// Zero-extends a cell to 64 bits.
+    fn extend(&mut self, value: Value) -> Value {
+        if self.cell_type == types::I64 {
+            value
+        } else {
+            self.builder.ins().uextend(types::I64, value)
+        }
+    }
+}
+
+#[test]
+fn test_execute_with_io() {
+    let ast = crate::parser::parse(",[.,]").unwrap();
+    let program = CraneliftCodegen::new(Options::default())
+        .compile(&ast)
+        .unwrap();
+
+    for &input in &["hello", "world"] {
+        let mut output = vec![];
+        program
+            .execute_with_io(&mut format!("{}\0", input).as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(output, input.as_bytes());
+    }
+}
+
+#[test]
+fn test_matches_interpreter() {
+    use crate::options::CellWidth;
+    use crate::Interpreter;
+
+    let program = "-->+++[<++>-]>,[>+<-][]>[-]++++++++[<++++++++>-]<+.>+++[>]";
+
+    for &cell_width in &[CellWidth::U8, CellWidth::U32] {
+        for &overflow in &[Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            let options = Options {
+                cell_width,
+                overflow,
+                checked: true,
+                count_instructions: true,
+                tape_size: 8,
+                ..Options::default()
+            };
+            let ast = crate::parser::parse(program).unwrap();
+
+            let mut expected = vec![];
+            let expected = Interpreter::new(options.clone())
+                .execute_with_io(&ast, &mut &b"\x07"[..], &mut expected)
+                .map(|state| (state, expected))
+                .map_err(|e| e.to_string());
+
+            let mut output = vec![];
+            let res = CraneliftCodegen::new(options)
+                .compile(&ast)
+                .unwrap()
+                .execute_with_io(&mut &b"\x07"[..], &mut output)
+                .map(|state| (state, output))
+                .map_err(|e| e.to_string());
+
+            assert_eq!(res, expected);
+        }
+    }
+}
+
+#[test]
+fn test_execute_from_blank() {
+    // the loop is dead on a blank tape only
+    let ast = crate::parser::parse("[>+<-]>.").unwrap();
+    let program = CraneliftCodegen::new(Options::default())
+        .compile(&ast)
+        .unwrap();
+    assert!(program.blank_entry.is_some());
+
+    let mut output = vec![];
+    program
+        .execute_from(&[3], 0, &mut &b""[..], &mut output)
+        .unwrap();
+    program.execute_with_io(&mut &b""[..], &mut output).unwrap();
+
+    assert_eq!(output, [3, 0]);
+}
+
+#[cfg(test)]
+fn compile_and_execute(
+    ast: &[BfAST],
+    options: &Options,
+    tape: &[u64],
+    pointer: usize,
+) -> MachineState {
+    CraneliftCodegen::new(options.clone())
+        .compile(ast)
+        .unwrap()
+        .execute_from(tape, pointer, &mut &b""[..], &mut vec![])
+        .unwrap()
+}
+
+#[test]
+fn test_division_loops() {
+    crate::interpreter::check_division_loops(compile_and_execute);
+}
+
+#[test]
+fn test_scans() {
+    crate::interpreter::check_scans(compile_and_execute);
+}
+
+#[test]
+fn test_large_prefix() {
+    // the tape left by the prefix is built into the program as a constant
+    let ast = crate::parser::parse("+[>+>+>+>+>+>+>+>+]").unwrap();
+    let program = CraneliftCodegen::new(Options {
+        tape_size: 1 << 22,
+        ..Options::default()
+    })
+    .compile(&ast)
+    .unwrap();
+
+    assert!(program.blank_entry.is_some());
+}
@@ -0,0 +1,507 @@
+//! Reference interpreter executing the AST directly, without LLVM.
+use crate::options::{Options, Overflow};
+use crate::parser::{BfAST, Position};
+use crate::runtime::{
+    check_initial_state, Environment, FinalState, InstructionCounts, MachineState, RuntimeError,
+    Tape,
+};
+use crate::Result;
+
+use std::io::{Read, Write};
+
+/// Interpreter of Brainfuck programs, honoring the same options as the compiled backends.
+///
+/// The bounds of the tape are always checked, so moving the pointer off the tape stops
+/// the program with [`RuntimeError::OutOfBounds`] even without
+/// [`Options::checked`](crate::Options::checked).
+pub struct Interpreter {
+    options: Options,
+}
+
+/// State of a program run at compile time up to its first input, by
+/// [`Interpreter::execute_prefix`].
+pub(crate) struct Prefix {
+    pub output: Vec<u8>,
+    /// Position of the first output instruction
+    pub output_position: Position,
+    pub tape: Vec<u64>,
+    pub pointer: usize,
+    /// Rest of the program to run from the state
+    pub residual: Vec<BfAST>,
+}
+
+/// Number of cells at the beginning of the tape on which prefixes run, bounding the memory
+/// of the evaluation and the tape built into the program.
+const PREFIX_TAPE_SIZE: usize = 1 << 16;
+
+/// Tape and pointer of a running program.
+struct Machine<'o> {
+    options: &'o Options,
+    // cells of the native width, like the tape of compiled programs
+    tape: Tape,
+    pointer: usize,
+    counts: InstructionCounts,
+}
+
+impl Interpreter {
+    pub fn new(options: Options) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Executes the program on the standard input and output.
+    pub fn execute(&self, ast: &[BfAST]) -> Result<MachineState> {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+
+        self.execute_with_io(ast, &mut stdin.lock(), &mut stdout.lock())
+    }
+
+    /// Executes the program, reading `,` from `input` and writing `.` to `output`.
+    pub fn execute_with_io(
+        &self,
+        ast: &[BfAST],
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.execute_from(ast, &[], 0, input, output)
+    }
+
+    /// Executes the program on a tape starting with the cells of `tape` and zero after,
+    /// with the pointer at `pointer`.
+    pub fn execute_from(
+        &self,
+        ast: &[BfAST],
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<MachineState> {
+        self.run_from(ast, tape, pointer, input, output)
+            .map(FinalState::into_machine_state)
+    }
+
+    /// Executes the program like [`Self::execute_from`], without reading the final tape.
+    pub(crate) fn run_from(
+        &self,
+        ast: &[BfAST],
+        tape: &[u64],
+        pointer: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<FinalState> {
+        check_initial_state(&self.options, tape, pointer)?;
+
+        let mut machine = Machine {
+            options: &self.options,
+            tape: Tape::new(self.options.tape_size, self.options.cell_width, tape)?,
+            pointer,
+            counts: InstructionCounts::default(),
+        };
+
+        let mut env = Environment::new(input, output, &self.options);
+        let res = machine.execute(ast, &mut env);
+
+        // the output before an error is kept
+        let flushed = env.flush();
+        res?;
+        flushed?;
+
+        Ok(FinalState {
+            tape: machine.tape,
+            pointer: machine.pointer,
+            counts: if self.options.count_instructions {
+                Some(machine.counts)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl Interpreter {
+    /// Runs the program on a blank tape until its first input or up to `steps` instructions
+    /// and loop iterations, collecting its output instead of writing it.
+    ///
+    /// The prefix also stops before moving the pointer past the first [`PREFIX_TAPE_SIZE`]
+    /// cells. Returns `None` if the program fails before, leaving the error to the full run.
+    pub(crate) fn execute_prefix(&self, ast: &[BfAST], steps: u64) -> Option<Prefix> {
+        let len = self.options.tape_size.min(PREFIX_TAPE_SIZE);
+        let mut machine = Machine {
+            options: &self.options,
+            tape: Tape::new(len, self.options.cell_width, &[]).ok()?,
+            pointer: 0,
+            counts: InstructionCounts::default(),
+        };
+        let mut prefix = Prefix {
+            output: vec![],
+            output_position: Position::default(),
+            tape: vec![],
+            pointer: 0,
+            residual: vec![],
+        };
+
+        let mut steps = steps;
+        prefix.residual = machine
+            .execute_prefix(ast, &mut prefix, &mut steps)
+            .ok()?
+            .unwrap_or_default();
+
+        prefix.tape = machine.tape.cells();
+        prefix.pointer = machine.pointer;
+
+        Some(prefix)
+    }
+}
+
+impl Machine<'_> {
+    /// Executes the program until its first input or out of steps, returning the rest of
+    /// the program then, or `None` once it has run to its end.
+    fn execute_prefix(
+        &mut self,
+        ast: &[BfAST],
+        prefix: &mut Prefix,
+        steps: &mut u64,
+    ) -> std::result::Result<Option<Vec<BfAST>>, RuntimeError> {
+        for (i, op) in ast.iter().enumerate() {
+            if *steps == 0 {
+                return Ok(Some(ast[i..].to_vec()));
+            }
+            *steps -= 1;
+
+            match *op {
+                // the input is only known when running
+                BfAST::GetChar(_) => return Ok(Some(ast[i..].to_vec())),
+                BfAST::LoopBlock(ref body, _) => {
+                    while self.tape.get(self.pointer) != 0 {
+                        if *steps == 0 {
+                            return Ok(Some(ast[i..].to_vec()));
+                        }
+                        *steps -= 1;
+
+                        // the rest of the body, then the loop again and the code after it
+                        if let Some(mut residual) = self.execute_prefix(body, prefix, steps)? {
+                            residual.extend_from_slice(&ast[i..]);
+                            return Ok(Some(residual));
+                        }
+                    }
+                }
+                BfAST::AddOp(k, position) => self.add(k as u64, position)?,
+                BfAST::SubOp(k, position) => self.sub(k as u64, position)?,
+                BfAST::AddPtr(k, position) => {
+                    // past the cells of the prefix, but still on the tape
+                    let pointer = self.pointer as u64 + k as u64;
+                    if pointer >= self.tape.len() as u64 && pointer < self.options.tape_size as u64
+                    {
+                        return Ok(Some(ast[i..].to_vec()));
+                    }
+
+                    self.move_pointer(k as i64, position)?
+                }
+                BfAST::SubPtr(k, position) => self.move_pointer(-(k as i64), position)?,
+                BfAST::PutChar(position) => {
+                    if prefix.output.is_empty() {
+                        prefix.output_position = position;
+                    }
+                    prefix.output.push(self.tape.get(self.pointer) as u8);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn execute(
+        &mut self,
+        ast: &[BfAST],
+        env: &mut Environment<'_>,
+    ) -> std::result::Result<(), RuntimeError> {
+        for op in ast {
+            match *op {
+                BfAST::LoopBlock(ref body, _) => {
+                    self.counts.loop_start += 1;
+
+                    while self.tape.get(self.pointer) != 0 {
+                        self.execute(body, env)?;
+                        self.counts.loop_end += 1;
+                    }
+                }
+                BfAST::AddOp(k, position) => {
+                    self.counts.add += k as u64;
+                    self.add(k as u64, position)?;
+                }
+                BfAST::SubOp(k, position) => {
+                    self.counts.sub += k as u64;
+                    self.sub(k as u64, position)?;
+                }
+                BfAST::AddPtr(k, position) => {
+                    self.counts.right += k as u64;
+                    self.move_pointer(k as i64, position)?;
+                }
+                BfAST::SubPtr(k, position) => {
+                    self.counts.left += k as u64;
+                    self.move_pointer(-(k as i64), position)?;
+                }
+                BfAST::PutChar(position) => {
+                    self.counts.output += 1;
+                    env.write_byte(self.tape.get(self.pointer) as u8)
+                        .map_err(|source| RuntimeError::Io { position, source })?;
+                }
+                BfAST::GetChar(position) => {
+                    self.counts.input += 1;
+                    let value = env
+                        .read_cell(self.tape.get(self.pointer))
+                        .map_err(|source| RuntimeError::Io { position, source })?;
+                    self.tape.set(self.pointer, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add(&mut self, k: u64, position: Position) -> std::result::Result<(), RuntimeError> {
+        let max = self.options.cell_width.max_value();
+        let cell = self.tape.get(self.pointer);
+
+        let value = match (
+            self.options.overflow,
+            cell.checked_add(k).filter(|&v| v <= max),
+        ) {
+            (_, Some(v)) => v,
+            (Overflow::Wrap, None) => cell.wrapping_add(k) & max,
+            (Overflow::Saturate, None) => max,
+            (Overflow::Trap, None) => return Err(RuntimeError::Overflow { position }),
+        };
+        self.tape.set(self.pointer, value);
+
+        Ok(())
+    }
+
+    fn sub(&mut self, k: u64, position: Position) -> std::result::Result<(), RuntimeError> {
+        let max = self.options.cell_width.max_value();
+        let cell = self.tape.get(self.pointer);
+
+        let value = match (self.options.overflow, cell.checked_sub(k)) {
+            (_, Some(v)) => v,
+            (Overflow::Wrap, None) => cell.wrapping_sub(k) & max,
+            (Overflow::Saturate, None) => 0,
+            (Overflow::Trap, None) => return Err(RuntimeError::Underflow { position }),
+        };
+        self.tape.set(self.pointer, value);
+
+        Ok(())
+    }
+
+    fn move_pointer(
+        &mut self,
+        offset: i64,
+        position: Position,
+    ) -> std::result::Result<(), RuntimeError> {
+        let pointer = self.pointer as i64 + offset;
+
+        if pointer < 0 || pointer >= self.tape.len() as i64 {
+            return Err(RuntimeError::OutOfBounds { position, pointer });
+        }
+
+        self.pointer = pointer as usize;
+
+        Ok(())
+    }
+}
+
+/// Checks a backend against the interpreter on the loops dividing the current cell, running
+/// `execute` on each program and its options from a tape and pointer.
+#[cfg(all(test, any(feature = "llvm", feature = "cranelift")))]
+pub(crate) fn check_division_loops<F>(mut execute: F)
+where
+    F: FnMut(&[BfAST], &Options, &[u64], usize) -> MachineState,
+{
+    use crate::options::CellWidth;
+
+    // the loops terminating on the cells: any cell for odd steps, even cells for even ones
+    // with wrapping, and any cell rounding up with saturation
+    let cases: &[(&str, Overflow, &[u64])] = &[
+        ("[--->+<]", Overflow::Wrap, &[1, 2, 3, 4, 7, 250]),
+        ("[++>+<]", Overflow::Wrap, &[2, 4, 6, 100]),
+        ("[-->+<]", Overflow::Wrap, &[2, 4, 6, 100]),
+        ("[--->+<]", Overflow::Saturate, &[1, 2, 3, 4, 7, 250]),
+        ("[-->+<]", Overflow::Saturate, &[1, 2, 3, 4, 7, 250]),
+    ];
+
+    for &(program, overflow, cells) in cases {
+        for &cell_width in &[CellWidth::U8, CellWidth::U16] {
+            let options = Options {
+                cell_width,
+                overflow,
+                ..Options::default()
+            };
+            let ast = crate::parser::parse(program).unwrap();
+
+            for &cell in cells {
+                let expected = Interpreter::new(options.clone())
+                    .execute_from(&ast, &[cell], 0, &mut &b""[..], &mut vec![])
+                    .unwrap();
+
+                assert_eq!(
+                    execute(&ast, &options, &[cell], 0),
+                    expected,
+                    "{} on {}",
+                    program,
+                    cell
+                );
+            }
+        }
+    }
+}
+
+/// Checks a backend against the interpreter on scans in both directions, like
+/// [`check_division_loops`].
+#[cfg(all(test, any(feature = "llvm", feature = "cranelift")))]
+pub(crate) fn check_scans<F>(mut execute: F)
+where
+    F: FnMut(&[BfAST], &Options, &[u64], usize) -> MachineState,
+{
+    use crate::options::CellWidth;
+
+    let tape = [0, 1, 0, 2, 3, 0, 4];
+    let cases = [("[<]", 4), ("[<]", 1), ("[<]", 6), ("[>]", 3), ("[<<]", 4)];
+
+    for &(program, pointer) in &cases {
+        for &cell_width in &[CellWidth::U8, CellWidth::U16] {
+            let options = Options {
+                cell_width,
+                tape_size: 8,
+                ..Options::default()
+            };
+            let ast = crate::parser::parse(program).unwrap();
+
+            let expected = Interpreter::new(options.clone())
+                .execute_from(&ast, &tape, pointer, &mut &b""[..], &mut vec![])
+                .unwrap();
+
+            assert_eq!(
+                execute(&ast, &options, &tape, pointer),
+                expected,
+                "{} from {}",
+                program,
+                pointer
+            );
+        }
+    }
+}
+
+#[test]
+fn test_execute_with_io() {
+    let ast = crate::parser::parse(",[.,]").unwrap();
+    let interpreter = Interpreter::new(Options::default());
+
+    let mut output = vec![];
+    interpreter
+        .execute_with_io(&ast, &mut &b"hello\x00"[..], &mut output)
+        .unwrap();
+
+    assert_eq!(output, b"hello");
+}
+
+#[test]
+fn test_overflow() {
+    use crate::options::CellWidth;
+    use crate::Error;
+
+    let ast = crate::parser::parse("-->+++[<++>-]").unwrap();
+
+    for &(cell_width, overflow, expected) in &[
+        (CellWidth::U8, Overflow::Wrap, Some(4)),
+        (CellWidth::U16, Overflow::Wrap, Some(4)),
+        (CellWidth::U8, Overflow::Saturate, Some(6)),
+        (CellWidth::U8, Overflow::Trap, None),
+    ] {
+        let interpreter = Interpreter::new(Options {
+            cell_width,
+            overflow,
+            ..Options::default()
+        });
+
+        match interpreter.execute_with_io(&ast, &mut &b""[..], &mut vec![]) {
+            Ok(state) => assert_eq!(state.tape.first().copied(), expected),
+            Err(Error::Runtime(RuntimeError::Underflow { position })) => {
+                assert_eq!(expected, None);
+                assert_eq!(position, Position { line: 1, column: 1 });
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
+
+#[test]
+fn test_oversized_tape() {
+    use crate::options::CellWidth;
+    use crate::Error;
+
+    // the size of the tape in bytes overflows
+    let interpreter = Interpreter::new(Options {
+        tape_size: (1 << 61) + 1,
+        cell_width: CellWidth::U64,
+        checked: true,
+        ..Options::default()
+    });
+    let ast = crate::parser::parse(">-").unwrap();
+
+    match interpreter.execute_with_io(&ast, &mut &b""[..], &mut vec![]) {
+        Err(Error::InvalidState(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_execute_prefix() {
+    // only the beginning of the tape is allocated
+    let interpreter = Interpreter::new(Options {
+        tape_size: 1 << 40,
+        ..Options::default()
+    });
+
+    let ast = crate::parser::parse("+.>++,").unwrap();
+    let prefix = interpreter.execute_prefix(&ast, 100).unwrap();
+    assert_eq!(prefix.output, [1]);
+    assert_eq!(prefix.tape, [1, 2]);
+    assert_eq!(prefix.pointer, 1);
+    assert_eq!(prefix.residual.len(), 1);
+
+    // stops before leaving it
+    let p = Position::default();
+    let ast = [
+        BfAST::AddOp(1, p),
+        BfAST::AddPtr(PREFIX_TAPE_SIZE, p),
+        BfAST::AddOp(1, p),
+    ];
+    let prefix = interpreter.execute_prefix(&ast, 100).unwrap();
+    assert_eq!(prefix.tape, [1]);
+    assert_eq!(prefix.residual.len(), 2);
+}
+
+#[test]
+fn test_run_large_tape() {
+    use std::time::{Duration, Instant};
+
+    // only the touched pages of the tape cost anything when the state is not read
+    let interpreter = Interpreter::new(Options {
+        tape_size: 1 << 30,
+        ..Options::default()
+    });
+    let ast = crate::parser::parse("+.").unwrap();
+
+    let start = Instant::now();
+    let mut output = vec![];
+    let state = interpreter
+        .run_from(&ast, &[], 0, &mut &b""[..], &mut output)
+        .unwrap();
+
+    assert_eq!(output, [1]);
+    assert_eq!(state.pointer, 0);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
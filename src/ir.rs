@@ -0,0 +1,167 @@
+//! Intermediate representation of the optimizer, lowered from [`BfAST`] before codegen.
+//!
+//! Operations address cells by their offset from the pointer, and the pointer moves of a
+//! run of operations are sunk to its end, so `>+>>-<<<` updates two cells and moves the
+//! pointer once.
+use crate::options::Options;
+use crate::parser::{BfAST, Position};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Adds to the cell at the offset.
+    Add(i64, u64, Position),
+    /// Subtracts from the cell at the offset.
+    Sub(i64, u64, Position),
+    /// Sets the cell at the offset to the value, which fits in a cell.
+    Set(i64, u64, Position),
+    /// Moves the pointer by the offset.
+    Move(i64, Position),
+    /// Writes the cell at the offset.
+    PutChar(i64, Position),
+    /// Reads into the cell at the offset.
+    GetChar(i64, Position),
+    /// Writes the bytes, a prefix of the program output computed at compile time.
+    Output(Vec<u8>, Position),
+    /// Sets the cells from the current one on to the values, a constant like the tape
+    /// computed at compile time.
+    Tape(Vec<u64>, Position),
+    /// Repeats the body while the current cell is not zero.
+    ///
+    /// The body leaves the pointer where it ends, like in the source.
+    Loop(Vec<Op>, Position),
+    /// Repeats the body like [`Op::Loop`] on a current cell known not to be zero, so the
+    /// body runs at least once without checking it.
+    EnteredLoop(Vec<Op>, Position),
+    /// Runs a loop subtracting the step from the current cell until it is zero and adding
+    /// the factor of each target to it per iteration, in closed form. Does nothing on zero.
+    ///
+    /// With wrapping, the loop runs forever on cells which are not a multiple of the largest
+    /// power of two dividing the step.
+    MulLoop(u64, Vec<MulTarget>, Position),
+    /// Clears the current cell like a loop stepping it, which only terminates on multiples
+    /// of the divisor. Runs forever on other cells with wrapping, and underflows at the
+    /// position when trapping.
+    ///
+    /// A divisor of zero is a loop never stepping the cell, like `[]`, which runs forever on
+    /// any cell but zero.
+    Clear(u64, Position),
+    /// Moves the pointer by the stride until the current cell is zero, like `[>]`.
+    ///
+    /// Forward scans of byte cells search the tape with `memchr`, and backward ones like `[<]`
+    /// with a runtime function, except in executables, as `memrchr` is not in every C
+    /// library. Other scans loop over the cells.
+    Scan(i64, Position),
+}
+
+/// A cell updated by [`Op::MulLoop`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MulTarget {
+    pub offset: i64,
+    pub factor: u64,
+    pub subtract: bool,
+    /// Position of the instruction over/underflowing the cell.
+    pub position: Position,
+}
+
+impl Op {
+    pub fn position(&self) -> Position {
+        match *self {
+            Op::Add(_, _, p)
+            | Op::Sub(_, _, p)
+            | Op::Set(_, _, p)
+            | Op::Move(_, p)
+            | Op::PutChar(_, p)
+            | Op::GetChar(_, p)
+            | Op::Output(_, p)
+            | Op::Tape(_, p)
+            | Op::Loop(_, p)
+            | Op::EnteredLoop(_, p)
+            | Op::MulLoop(_, _, p)
+            | Op::Clear(_, p)
+            | Op::Scan(_, p) => p,
+        }
+    }
+}
+
+/// Lowers the AST into the IR.
+///
+/// Pointer moves stay in place when the bounds are checked or instructions are counted,
+/// as both observe every move.
+pub fn lower(ast: &[BfAST], options: &Options) -> Vec<Op> {
+    let sink_moves = !options.checked && !options.count_instructions;
+    lower_block(ast, sink_moves)
+}
+
+fn lower_block(ast: &[BfAST], sink_moves: bool) -> Vec<Op> {
+    let mut ops = vec![];
+    // pending move of the pointer, and the position of its first instruction
+    let mut offset = 0;
+    let mut moved = None;
+
+    for node in ast {
+        let delta = match *node {
+            BfAST::AddPtr(k, _) => k as i64,
+            BfAST::SubPtr(k, _) => -(k as i64),
+            _ => 0,
+        };
+
+        match *node {
+            BfAST::AddPtr(_, position) | BfAST::SubPtr(_, position) => {
+                if sink_moves {
+                    offset += delta;
+                    moved.get_or_insert(position);
+                } else {
+                    ops.push(Op::Move(delta, position));
+                }
+            }
+            BfAST::AddOp(k, position) => ops.push(Op::Add(offset, k as u64, position)),
+            BfAST::SubOp(k, position) => ops.push(Op::Sub(offset, k as u64, position)),
+            BfAST::PutChar(position) => ops.push(Op::PutChar(offset, position)),
+            BfAST::GetChar(position) => ops.push(Op::GetChar(offset, position)),
+            BfAST::LoopBlock(ref body, position) => {
+                flush_move(&mut ops, &mut offset, &mut moved);
+                ops.push(Op::Loop(lower_block(body, sink_moves), position));
+            }
+        }
+    }
+
+    flush_move(&mut ops, &mut offset, &mut moved);
+
+    ops
+}
+
+pub(crate) fn flush_move(ops: &mut Vec<Op>, offset: &mut i64, moved: &mut Option<Position>) {
+    if let Some(position) = moved.take() {
+        if *offset != 0 {
+            ops.push(Op::Move(*offset, position));
+        }
+    }
+
+    *offset = 0;
+}
+
+#[test]
+fn test_lower() {
+    let ast = crate::parser::parse(">+>>-<<[>.<-]>>").unwrap();
+    let p = |column| Position { line: 1, column };
+
+    assert_eq!(
+        lower(&ast, &Options::default()),
+        vec![
+            Op::Add(1, 1, p(2)),
+            Op::Sub(3, 1, p(5)),
+            Op::Move(1, p(1)),
+            Op::Loop(vec![Op::PutChar(1, p(10)), Op::Sub(0, 1, p(12))], p(8)),
+            Op::Move(2, p(14)),
+        ]
+    );
+
+    let checked = Options {
+        checked: true,
+        ..Options::default()
+    };
+    assert_eq!(
+        lower(&crate::parser::parse(">+<").unwrap(), &checked),
+        vec![Op::Move(1, p(1)), Op::Add(0, 1, p(2)), Op::Move(-1, p(3))]
+    );
+}
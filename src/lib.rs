@@ -0,0 +1,189 @@
+//! Brainfuck implementation in Rust
+//!
+//! Programs are JIT-compiled with LLVM by default. Without the `llvm` feature, they are
+//! run by the [`Interpreter`], which needs no LLVM installation. The `cranelift` feature
+//! adds a JIT backend with Cranelift, compiling faster than LLVM.
+#[macro_use]
+extern crate pest_derive;
+use thiserror::Error;
+
+#[cfg(feature = "llvm")]
+pub mod aot;
+#[cfg(feature = "llvm")]
+pub mod codegen;
+#[cfg(feature = "cranelift")]
+pub mod cranelift_backend;
+pub mod interpreter;
+pub mod ir;
+pub mod optimizer;
+pub mod options;
+pub mod parser;
+pub mod runtime;
+
+#[cfg(feature = "llvm")]
+pub use aot::Emit;
+#[cfg(feature = "llvm")]
+pub use codegen::{Codegen, CompiledProgram};
+#[cfg(feature = "cranelift")]
+pub use cranelift_backend::{CraneliftCodegen, CraneliftProgram};
+#[cfg(feature = "llvm")]
+pub use inkwell::context::Context;
+pub use interpreter::Interpreter;
+pub use options::{Backend, Buffering, CellWidth, Eof, Options, Overflow};
+pub use parser::{BfAST, Position};
+pub use runtime::{InstructionCounts, MachineState};
+
+#[macro_export]
+macro_rules! ice {
+    ($($x: expr),*) => {
+        return Err(Error::ice(format!($($x),*)));
+    };
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{0}")]
+    ParseError(parser::ParseError),
+    #[error("internal compiler error: {0}")]
+    Ice(std::borrow::Cow<'static, str>),
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
+    #[error("LLVM error: {0}")]
+    Llvm(String),
+    #[error("Cranelift error: {0}")]
+    Cranelift(String),
+    #[error("linker exited with {0}")]
+    Link(std::process::ExitStatus),
+    #[error("runtime error: {0}")]
+    Runtime(runtime::RuntimeError),
+    #[error("invalid initial state: {0}")]
+    InvalidState(String),
+}
+
+impl Error {
+    pub fn ice<S: Into<std::borrow::Cow<'static, str>>>(message: S) -> Error {
+        Error::Ice(message.into())
+    }
+}
+
+impl From<parser::ParseError> for Error {
+    fn from(p: parser::ParseError) -> Self {
+        Self::ParseError(p)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<runtime::RuntimeError> for Error {
+    fn from(e: runtime::RuntimeError) -> Self {
+        Self::Runtime(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Parses a Brainfuck program into its AST.
+pub fn parse<P: AsRef<str>>(program: P) -> Result<Vec<BfAST>> {
+    parser::parse(program)
+}
+
+/// Compiles and optimizes the AST into a program, ready to be executed any number of times
+/// with [`CompiledProgram::execute`].
+#[cfg(feature = "llvm")]
+pub fn compile<'c>(
+    context: &'c Context,
+    ast: &[BfAST],
+    options: &Options,
+) -> Result<CompiledProgram<'c>> {
+    let program = Codegen::with_options(context, options.clone())?.compile(ast)?;
+    program.optimize();
+
+    Ok(program)
+}
+
+/// Parses and runs a Brainfuck program with the backend of the options.
+pub fn run<P: AsRef<str>>(program: P, options: &Options) -> Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+
+    // the final state is not read, as that scans the whole tape
+    run_program(program, options, &mut stdin.lock(), &mut stdout.lock()).map(|_| ())
+}
+
+/// Parses and runs a Brainfuck program with the backend of the options on the given input
+/// and output, returning the final state of the machine.
+pub fn run_with_io<P: AsRef<str>>(
+    program: P,
+    options: &Options,
+    input: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<MachineState> {
+    run_program(program, options, input, output).map(runtime::FinalState::into_machine_state)
+}
+
+fn run_program<P: AsRef<str>>(
+    program: P,
+    options: &Options,
+    input: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<runtime::FinalState> {
+    let ast = parse(program)?;
+
+    match options.backend {
+        #[cfg(feature = "llvm")]
+        Backend::Llvm => {
+            let context = Context::create();
+            compile(&context, &ast, options)?.run_from(&[], 0, input, output)
+        }
+        #[cfg(feature = "cranelift")]
+        Backend::Cranelift => CraneliftCodegen::new(options.clone())
+            .compile(&ast)?
+            .run_from(&[], 0, input, output),
+        Backend::Interpreter => {
+            Interpreter::new(options.clone()).run_from(&ast, &[], 0, input, output)
+        }
+    }
+}
+
+/// Parses and compiles a Brainfuck program into a native executable.
+#[cfg(feature = "llvm")]
+pub fn build<P: AsRef<str>>(program: P, options: &Options, output: &std::path::Path) -> Result<()> {
+    let ast = parse(program)?;
+
+    let context = Context::create();
+    compile(&context, &ast, options)?.write_executable(output)
+}
+
+/// Parses and compiles a Brainfuck program, writing the module in each of the given formats
+/// before (`<stem>.<ext>`) and after (`<stem>.opt.<ext>`) the optimization pipeline.
+#[cfg(feature = "llvm")]
+pub fn emit<P: AsRef<str>>(
+    program: P,
+    options: &Options,
+    kinds: &[Emit],
+    stem: &std::path::Path,
+) -> Result<()> {
+    let ast = parse(program)?;
+
+    let context = Context::create();
+    let program = Codegen::with_options(&context, options.clone())?.compile(&ast)?;
+
+    for &kind in kinds {
+        program.emit(kind, &stem.with_extension(kind.extension()))?;
+    }
+
+    program.optimize();
+
+    for &kind in kinds {
+        program.emit(
+            kind,
+            &stem.with_extension(format!("opt.{}", kind.extension())),
+        )?;
+    }
+
+    Ok(())
+}
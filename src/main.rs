@@ -1,55 +1,185 @@
-//! Brainfuck implementation in Rust
-#[macro_use]
-extern crate pest_derive;
-use thiserror::Error;
-
-pub mod codegen;
-pub mod parser;
-
-#[macro_export]
-macro_rules! ice {
-    ($($x: expr),*) => {
-        return Err(Error::ice(format!($($x),*)));
-    };
+//! Command line interface of bf-rs
+//!
+//! ```text
+//! bf-rs [run] <file> [options]
+//! bf-rs build <file> [-o <output>] [options]
+//! ```
+//!
+//! Options:
+//!
+//! * `--backend=llvm|cranelift|interpreter`: backend running the program
+//! * `--opt`: enables optimizations
+//! * `--tape-size=<cells>`: number of cells of the tape
+//! * `--checked`: stops with an error when the pointer leaves the tape
+//! * `--cell-width=8|16|32|64`: number of bits of a cell
+//! * `--overflow=wrap|saturate|trap`: semantics of cell arithmetic beyond its bounds
+//! * `--eof=zero|max|unchanged`: value read by `,` at the end of input
+//! * `--buffering=auto|full|line|none`: when the output is flushed besides input and exit
+//! * `--prefix-steps=<steps>`: steps run at compile time before the first input
+//! * `-v`, `--verbose`: reports the progress of compilation on stderr
+//!
+//! With `--emit=llvm-ir|bc|asm[,...]`, the module is written next to the output (or the
+//! source file) before and after optimization instead of being run or linked.
+//!
+//! `build` and `--emit` require the `llvm` feature.
+use std::path::PathBuf;
+
+enum Command {
+    Run,
+    #[cfg(feature = "llvm")]
+    Build,
 }
 
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error("{0}")]
-    ParseError(parser::ParseError),
-    #[error("internal compiler error: {0}")]
-    Ice(std::borrow::Cow<'static, str>),
+struct Args {
+    command: Command,
+    file: PathBuf,
+    #[cfg(feature = "llvm")]
+    output: Option<PathBuf>,
+    options: bf_rs::Options,
+    #[cfg(feature = "llvm")]
+    emit: Vec<bf_rs::Emit>,
 }
 
-impl Error {
-    pub fn ice<S: Into<std::borrow::Cow<'static, str>>>(message: S) -> Error {
-        Error::Ice(message.into())
+// `-o` takes the next argument only with the llvm feature
+#[cfg_attr(not(feature = "llvm"), allow(clippy::while_let_on_iterator))]
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1).peekable();
+
+    let command = match args.peek().map(String::as_str) {
+        Some("run") => {
+            args.next();
+            Command::Run
+        }
+        #[cfg(feature = "llvm")]
+        Some("build") => {
+            args.next();
+            Command::Build
+        }
+        #[cfg(not(feature = "llvm"))]
+        Some("build") => return Err("build requires the llvm feature".into()),
+        _ => Command::Run,
+    };
+
+    let mut file = None;
+    #[cfg(feature = "llvm")]
+    let mut output = None;
+    let mut options = bf_rs::Options::default();
+    #[cfg(feature = "llvm")]
+    let mut emit = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            #[cfg(feature = "llvm")]
+            "-o" => output = Some(args.next().ok_or("-o requires a file name")?.into()),
+            _ if arg.starts_with("--backend=") => {
+                options.backend = arg["--backend=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--opt") => options.optimized = true,
+            "--checked" => options.checked = true,
+            "-v" | "--verbose" => options.verbose = true,
+            _ if arg.starts_with("--cell-width=") => {
+                options.cell_width = arg["--cell-width=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--overflow=") => {
+                options.overflow = arg["--overflow=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--eof=") => {
+                options.eof = arg["--eof=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--buffering=") => {
+                options.buffering = arg["--buffering=".len()..].parse()?;
+            }
+            _ if arg.starts_with("--prefix-steps=") => {
+                options.prefix_steps = match arg["--prefix-steps=".len()..].parse() {
+                    Ok(steps) => steps,
+                    _ => return Err(format!("Invalid number of steps: {}", arg)),
+                };
+            }
+            _ if arg.starts_with("--tape-size=") => {
+                options.tape_size = match arg["--tape-size=".len()..].parse() {
+                    Ok(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid tape size: {}", arg)),
+                };
+            }
+            #[cfg(feature = "llvm")]
+            _ if arg.starts_with("--emit=") => {
+                for kind in arg["--emit=".len()..].split(',') {
+                    emit.push(kind.parse::<bf_rs::Emit>()?);
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if file.is_none() => file = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
     }
+
+    Ok(Args {
+        command,
+        file: file.ok_or("No file specified")?,
+        #[cfg(feature = "llvm")]
+        output,
+        options,
+        #[cfg(feature = "llvm")]
+        emit,
+    })
 }
 
-impl From<parser::ParseError> for Error {
-    fn from(p: parser::ParseError) -> Self {
-        Self::ParseError(p)
+/// Whether both paths name the same file, which exists.
+#[cfg(feature = "llvm")]
+fn is_same_file(a: &std::path::Path, b: &std::path::Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
     }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
-
 fn main() {
-    use crate::codegen::Codegen;
-    use inkwell::context::Context;
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}. Abort.", e);
+            std::process::exit(1);
+        }
+    };
 
-    let mut args = std::env::args();
-    if args.len() < 2 {
-        eprintln!("No file specified. Abort.");
-        return;
-    }
+    let program = match std::fs::read_to_string(&args.file) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", args.file.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    #[cfg(feature = "llvm")]
+    let output = match args.output {
+        Some(ref output) => output.clone(),
+        None => args.file.with_extension(""),
+    };
 
-    let res = parser::parse(std::fs::read_to_string(args.nth(1).unwrap()).unwrap()).unwrap();
-    let opt_flag = args.nth(0).map(|v| v.starts_with("--opt")).unwrap_or(false);
+    // the default output of an extension-less source file is the file itself
+    #[cfg(feature = "llvm")]
+    {
+        if let Command::Build = args.command {
+            if args.emit.is_empty() && is_same_file(&args.file, &output) {
+                eprintln!(
+                    "{}: the executable would overwrite the source file; name it with -o. Abort.",
+                    args.file.display()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let ctx = Context::create();
-    let codegen = Codegen::new(&ctx, opt_flag).unwrap();
+    let res = match args.command {
+        #[cfg(feature = "llvm")]
+        _ if !args.emit.is_empty() => bf_rs::emit(program, &args.options, &args.emit, &output),
+        Command::Run => bf_rs::run(program, &args.options),
+        #[cfg(feature = "llvm")]
+        Command::Build => bf_rs::build(program, &args.options, &output),
+    };
 
-    codegen.run(&res).unwrap();
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,738 @@
+//! Optimization passes on the [IR](crate::ir).
+use crate::interpreter::Interpreter;
+use crate::ir::{self, MulTarget, Op};
+use crate::options::{Options, Overflow};
+use crate::parser::{BfAST, Position};
+use std::collections::BTreeMap;
+
+/// Optimizes the IR of a program.
+///
+/// Does nothing when instructions are counted, as the optimized loops skip their bodies.
+/// When the bounds are checked, loop bodies keep their moves, so only clears are optimized.
+pub fn optimize(ops: Vec<Op>, options: &Options) -> Vec<Op> {
+    if options.count_instructions {
+        return ops;
+    }
+
+    let ops = optimize_block(ops, options);
+    let ops = propagate(ops, &mut KnownTape::unknown(), options);
+    sink_moves(ops, options)
+}
+
+/// Lowers and optimizes a program for a blank tape, on which every cell is zero.
+///
+/// The program is run at compile time up to its first input, within
+/// [`Options::prefix_steps`], so only its output and tape are left of that prefix. The rest
+/// of the program then removes the loops known never to be entered from there.
+pub fn specialize_blank(ast: &[BfAST], options: &Options) -> Vec<Op> {
+    if options.count_instructions {
+        return ir::lower(ast, options);
+    }
+
+    let mut ops = vec![];
+    let prefix = match options.prefix_steps {
+        0 => None,
+        steps => Interpreter::new(options.clone()).execute_prefix(ast, steps),
+    };
+
+    let residual = match prefix {
+        Some(prefix) => {
+            if !prefix.output.is_empty() {
+                ops.push(Op::Output(prefix.output, prefix.output_position));
+            }
+
+            // the pointer starts at the first cell
+            let position = ast.first().map_or(Position::default(), BfAST::position);
+            if !prefix.tape.is_empty() {
+                ops.push(Op::Tape(prefix.tape, position));
+            }
+            if prefix.pointer != 0 {
+                ops.push(Op::Move(prefix.pointer as i64, position));
+            }
+
+            prefix.residual
+        }
+        None => ast.to_vec(),
+    };
+
+    ops.extend(optimize_block(ir::lower(&residual, options), options));
+    let ops = propagate(ops, &mut KnownTape::blank(), options);
+    sink_moves(ops, options)
+}
+
+fn optimize_block(ops: Vec<Op>, options: &Options) -> Vec<Op> {
+    let ops = ops
+        .into_iter()
+        .map(|op| match op {
+            Op::Loop(body, position) => optimize_loop(body, position, options),
+            op => op,
+        })
+        .collect();
+
+    canonicalize(ops, options)
+}
+
+/// Merges the arithmetic on each cell, like `+++--` into `+` and `[-]++` into a set of 2.
+///
+/// Opposing moves are already cancelled by the lowering, which sinks them. With wrapping,
+/// the arithmetic of a run between other operations is merged per cell, as it cannot fail.
+/// Otherwise, only adjacent operations are merged, and only when they over/underflow like
+/// the original ones.
+fn canonicalize(ops: Vec<Op>, options: &Options) -> Vec<Op> {
+    let wrap = options.overflow == Overflow::Wrap;
+    let mut canonical: Vec<Op> = vec![];
+
+    for op in ops {
+        let (offset, k, subtract) = match op {
+            Op::Add(offset, k, _) => (offset, k, false),
+            Op::Sub(offset, k, _) => (offset, k, true),
+            op => {
+                canonical.push(op);
+                continue;
+            }
+        };
+
+        // the last operation on the cell which can be merged with, if any
+        let mut index = None;
+        for (i, prev) in canonical.iter().enumerate().rev() {
+            let prev_offset = match *prev {
+                Op::Add(offset, _, _) | Op::Sub(offset, _, _) | Op::Set(offset, _, _) => offset,
+                Op::Clear(1, _) => 0,
+                _ => break,
+            };
+
+            if prev_offset == offset {
+                index = Some(i);
+                break;
+            }
+
+            if !wrap {
+                break;
+            }
+        }
+
+        let merged = match index {
+            Some(i) => merge(&canonical[i], k, subtract, options),
+            None => None,
+        };
+
+        match (index, merged) {
+            (Some(i), Some(Some(merged))) => canonical[i] = merged,
+            (Some(i), Some(None)) => {
+                canonical.remove(i);
+            }
+            _ => canonical.push(op),
+        }
+    }
+
+    canonical
+}
+
+/// Merges adding (or subtracting) `k` to a cell into the previous operation on it, returning
+/// `None` if they cannot be merged, or `Some(None)` if they cancel out.
+fn merge(prev: &Op, k: u64, subtract: bool, options: &Options) -> Option<Option<Op>> {
+    let (offset, prev_k, prev_subtract, position) = match *prev {
+        Op::Add(offset, prev_k, position) => (offset, prev_k, false, position),
+        Op::Sub(offset, prev_k, position) => (offset, prev_k, true, position),
+        Op::Set(offset, value, position) => {
+            return set_value(value, k, subtract, options)
+                .map(|v| Some(Op::Set(offset, v, position)))
+        }
+        Op::Clear(1, position) => {
+            return set_value(0, k, subtract, options).map(|v| Some(Op::Set(0, v, position)))
+        }
+        _ => return None,
+    };
+
+    let (net, subtract) = match options.overflow {
+        Overflow::Wrap => {
+            let max = options.cell_width.max_value();
+            let prev_delta = if prev_subtract {
+                prev_k.wrapping_neg()
+            } else {
+                prev_k
+            };
+            let delta = if subtract { k.wrapping_neg() } else { k };
+            (prev_delta.wrapping_add(delta) & max, false)
+        }
+        // saturating runs in one direction saturate like their sum
+        Overflow::Saturate if prev_subtract == subtract => (prev_k.saturating_add(k), subtract),
+        _ => return None,
+    };
+
+    Some(match (net, subtract) {
+        (0, _) => None,
+        (net, false) => Some(Op::Add(offset, net, position)),
+        (net, true) => Some(Op::Sub(offset, net, position)),
+    })
+}
+
+/// Adds (or subtracts) `k` to a known value of a cell, or returns `None` if it traps.
+fn set_value(value: u64, k: u64, subtract: bool, options: &Options) -> Option<u64> {
+    let max = options.cell_width.max_value();
+
+    let res = if subtract {
+        value.checked_sub(k)
+    } else {
+        value.checked_add(k).filter(|&res| res <= max)
+    };
+
+    match (res, options.overflow) {
+        (Some(res), _) => Some(res),
+        (None, Overflow::Wrap) if subtract => Some(value.wrapping_sub(k) & max),
+        (None, Overflow::Wrap) => Some(value.wrapping_add(k) & max),
+        (None, Overflow::Saturate) if subtract => Some(0),
+        (None, Overflow::Saturate) => Some(max),
+        (None, Overflow::Trap) => None,
+    }
+}
+
+fn optimize_loop(body: Vec<Op>, position: Position, options: &Options) -> Op {
+    if let Some((step, targets)) = mul_loop(&body, options) {
+        return Op::MulLoop(step, targets, position);
+    }
+
+    if let Some(divisor) = clear_loop(&body, options) {
+        let position = body.first().map_or(position, Op::position);
+        return Op::Clear(divisor, position);
+    }
+
+    match body[..] {
+        // scans skip the bounds checks of the moves
+        [Op::Move(stride, _)] if !options.checked => Op::Scan(stride, position),
+        _ => Op::Loop(optimize_block(body, options), position),
+    }
+}
+
+/// Value of a cell known at compile time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Known {
+    Value(u64),
+    NonZero,
+    Unknown,
+}
+
+/// Cells known at a point of the program.
+struct KnownTape {
+    // by their offset from the pointer at the start, so moves do not renumber them
+    cells: BTreeMap<i64, Known>,
+    pointer: i64,
+    /// whether the other cells are zero
+    blank: bool,
+}
+
+impl KnownTape {
+    fn unknown() -> Self {
+        KnownTape {
+            cells: BTreeMap::new(),
+            pointer: 0,
+            blank: false,
+        }
+    }
+
+    fn blank() -> Self {
+        KnownTape {
+            cells: BTreeMap::new(),
+            pointer: 0,
+            blank: true,
+        }
+    }
+
+    fn get(&self, offset: i64) -> Known {
+        match self.cells.get(&(self.pointer + offset)) {
+            Some(&known) => known,
+            None if self.blank => Known::Value(0),
+            None => Known::Unknown,
+        }
+    }
+
+    fn set(&mut self, offset: i64, known: Known) {
+        self.cells.insert(self.pointer + offset, known);
+    }
+
+    fn add(&mut self, offset: i64, k: u64, subtract: bool, options: &Options) {
+        // a trapping operation ends the program
+        let known = match self.get(offset) {
+            Known::Value(value) => {
+                set_value(value, k, subtract, options).map_or(Known::Unknown, Known::Value)
+            }
+            _ => Known::Unknown,
+        };
+        self.set(offset, known);
+    }
+
+    fn shift(&mut self, offset: i64) {
+        self.pointer += offset;
+    }
+
+    /// Forgets every cell but the current one, which a loop leaves at zero.
+    fn exit_loop(&mut self) {
+        *self = KnownTape::unknown();
+        self.set(0, Known::Value(0));
+    }
+}
+
+/// Propagates the known values of cells through the program, removing the loops and clears
+/// of cells known to be zero, and marking the loops on cells known not to be zero as
+/// entered.
+///
+/// The current cell is zero after a loop, and not zero at the start of its body. A loop
+/// may move the pointer or change any cell, so the other cells are unknown after it.
+fn propagate(ops: Vec<Op>, tape: &mut KnownTape, options: &Options) -> Vec<Op> {
+    let mut propagated = vec![];
+
+    for op in ops {
+        let current = tape.get(0);
+
+        match op {
+            // never entered, and clears of zero do nothing
+            Op::Loop(..) | Op::EnteredLoop(..) | Op::MulLoop(..) | Op::Clear(..) | Op::Scan(..)
+                if current == Known::Value(0) =>
+            {
+                continue
+            }
+            Op::Set(offset, value, _) if tape.get(offset) == Known::Value(value) => continue,
+            Op::Loop(body, position) | Op::EnteredLoop(body, position) => {
+                let mut body_tape = KnownTape::unknown();
+                body_tape.set(0, Known::NonZero);
+                let body = propagate(body, &mut body_tape, options);
+
+                propagated.push(match current {
+                    Known::Value(_) | Known::NonZero => Op::EnteredLoop(body, position),
+                    Known::Unknown => Op::Loop(body, position),
+                });
+                tape.exit_loop();
+                continue;
+            }
+            Op::MulLoop(_, ref targets, _) => {
+                for target in targets {
+                    tape.set(target.offset, Known::Unknown);
+                }
+                tape.set(0, Known::Value(0));
+            }
+            Op::Clear(..) => tape.set(0, Known::Value(0)),
+            Op::Scan(..) => tape.exit_loop(),
+            Op::Add(offset, k, _) => tape.add(offset, k, false, options),
+            Op::Sub(offset, k, _) => tape.add(offset, k, true, options),
+            Op::Set(offset, value, _) => tape.set(offset, Known::Value(value)),
+            Op::Tape(ref cells, _) => {
+                for (i, &value) in cells.iter().enumerate() {
+                    tape.set(i as i64, Known::Value(value));
+                }
+            }
+            Op::Move(offset, _) => tape.shift(offset),
+            Op::GetChar(offset, _) => tape.set(offset, Known::Unknown),
+            Op::PutChar(..) | Op::Output(..) => {}
+        }
+
+        propagated.push(op);
+    }
+
+    propagated
+}
+
+/// Sinks the moves of each run again and merges its arithmetic, once [`propagate`] removed
+/// the loops and clears between them, so `>[-]<` on a blank tape does nothing.
+fn sink_moves(ops: Vec<Op>, options: &Options) -> Vec<Op> {
+    let mut sunk = vec![];
+    // pending move of the pointer, and the position of its first instruction
+    let mut offset = 0;
+    let mut moved = None;
+
+    for op in ops {
+        let op = match op {
+            Op::Move(delta, position) if !options.checked => {
+                offset += delta;
+                moved.get_or_insert(position);
+                continue;
+            }
+            Op::Add(o, k, position) => Op::Add(o + offset, k, position),
+            Op::Sub(o, k, position) => Op::Sub(o + offset, k, position),
+            Op::Set(o, value, position) => Op::Set(o + offset, value, position),
+            Op::PutChar(o, position) => Op::PutChar(o + offset, position),
+            Op::GetChar(o, position) => Op::GetChar(o + offset, position),
+            Op::Output(..) => op,
+            Op::Loop(body, position) => {
+                ir::flush_move(&mut sunk, &mut offset, &mut moved);
+                Op::Loop(sink_moves(body, options), position)
+            }
+            Op::EnteredLoop(body, position) => {
+                ir::flush_move(&mut sunk, &mut offset, &mut moved);
+                Op::EnteredLoop(sink_moves(body, options), position)
+            }
+            op => {
+                ir::flush_move(&mut sunk, &mut offset, &mut moved);
+                op
+            }
+        };
+        sunk.push(op);
+    }
+
+    ir::flush_move(&mut sunk, &mut offset, &mut moved);
+
+    canonicalize(sunk, options)
+}
+
+/// Recognizes a loop only stepping the current cell, like `[-]`, `[+]` or `[---]`,
+/// returning the divisor of the cells on which it terminates, or zero for loops like `[]`
+/// which only terminate on zero.
+///
+/// With wrapping, the loop terminates on multiples of the largest power of two dividing the
+/// step, and never on other cells. Otherwise, only loops subtracting a constant are clears,
+/// which underflow on other cells than multiples of the constant when trapping.
+fn clear_loop(body: &[Op], options: &Options) -> Option<u64> {
+    let max = options.cell_width.max_value();
+
+    match options.overflow {
+        _ if body.is_empty() => Some(0),
+        Overflow::Wrap => {
+            let mut step = 0u64;
+
+            for op in body {
+                step = match *op {
+                    Op::Add(0, k, _) => step.wrapping_add(k),
+                    Op::Sub(0, k, _) => step.wrapping_sub(k),
+                    _ => return None,
+                } & max;
+            }
+
+            // runs forever on any cell but zero
+            if step == 0 {
+                return Some(0);
+            }
+
+            Some(1 << step.trailing_zeros())
+        }
+        Overflow::Saturate => match *body {
+            [Op::Sub(0, _, _)] => Some(1),
+            _ => None,
+        },
+        Overflow::Trap => match *body {
+            [Op::Sub(0, k, _)] => Some(k),
+            _ => None,
+        },
+    }
+}
+
+/// Recognizes a loop whose body only adds constants to cells, like `[->+>++>>---<<<<]` or
+/// `[--->+<]`, returning the step subtracted from the current cell per iteration and the
+/// cells it adds the number of iterations into.
+///
+/// With wrapping, the current cell may step by any amount in either direction. Otherwise,
+/// it must be decremented once and every cell updated once to keep the overflow of the
+/// loop, and a trapping loop may only decrement by one and have one target, which
+/// overflows first.
+fn mul_loop(body: &[Op], options: &Options) -> Option<(u64, Vec<MulTarget>)> {
+    let max = options.cell_width.max_value();
+    let wrap = options.overflow == Overflow::Wrap;
+
+    // step of the current cell per iteration (modulo the cell size), and its instructions
+    let mut step = 0u64;
+    let mut counters = vec![];
+    let mut targets: Vec<MulTarget> = vec![];
+
+    for op in body {
+        let (offset, k, subtract, position) = match *op {
+            Op::Add(offset, k, position) => (offset, k, false, position),
+            Op::Sub(offset, k, position) => (offset, k, true, position),
+            _ => return None,
+        };
+
+        let delta = if subtract {
+            k.wrapping_neg() & max
+        } else {
+            k & max
+        };
+
+        if offset == 0 {
+            step = step.wrapping_sub(delta) & max;
+            counters.push((k, subtract));
+            continue;
+        }
+
+        match targets.iter_mut().find(|target| target.offset == offset) {
+            Some(target) if wrap => target.factor = target.factor.wrapping_add(delta) & max,
+            Some(_) => return None,
+            None if wrap => targets.push(MulTarget {
+                offset,
+                factor: delta,
+                subtract: false,
+                position,
+            }),
+            None => targets.push(MulTarget {
+                offset,
+                factor: k,
+                subtract,
+                position,
+            }),
+        }
+    }
+
+    let step = match counters[..] {
+        _ if wrap => {
+            targets.retain(|target| target.factor != 0);
+            step
+        }
+        [(k, true)] => k,
+        _ => return None,
+    };
+
+    // a loop not stepping the cell never terminates, and clears are left to the loop
+    if step == 0 || targets.is_empty() {
+        return None;
+    }
+
+    if options.overflow == Overflow::Trap && (step != 1 || targets.len() > 1) {
+        return None;
+    }
+
+    Some((step, targets))
+}
+
+/// Computes the inverse of an odd number modulo 2^64, and so modulo any power of two.
+#[cfg_attr(not(any(feature = "llvm", feature = "cranelift")), allow(dead_code))]
+pub(crate) fn modular_inverse(odd: u64) -> u64 {
+    // each Newton iteration doubles the correct low bits, starting from 3
+    let mut inverse = odd;
+
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(odd.wrapping_mul(inverse)));
+    }
+
+    inverse
+}
+
+#[test]
+fn test_mul_loop() {
+    let options = Options::default();
+    let optimize = |program: &str, options: &Options| {
+        let ast = crate::parser::parse(program).unwrap();
+        optimize(crate::ir::lower(&ast, options), options)
+    };
+    let p = |column| Position { line: 1, column };
+
+    assert_eq!(
+        optimize("[->+>++>>---<<<<]", &options),
+        vec![Op::MulLoop(
+            1,
+            vec![
+                MulTarget {
+                    offset: 1,
+                    factor: 1,
+                    subtract: false,
+                    position: p(4),
+                },
+                MulTarget {
+                    offset: 2,
+                    factor: 2,
+                    subtract: false,
+                    position: p(6),
+                },
+                MulTarget {
+                    offset: 4,
+                    factor: 253,
+                    subtract: false,
+                    position: p(10),
+                },
+            ],
+            p(1),
+        )]
+    );
+
+    // the counter may step by any amount with wrapping only, and so be updated twice
+    assert!(matches!(
+        optimize("[++>+<+]", &options)[0],
+        Op::MulLoop(253, _, _)
+    ));
+    assert!(matches!(optimize("[+-]", &options)[0], Op::Clear(0, _)));
+
+    let saturate = Options {
+        overflow: Overflow::Saturate,
+        ..Options::default()
+    };
+    assert!(matches!(optimize("[->+<-+]", &saturate)[0], Op::Loop(..)));
+    assert!(matches!(
+        optimize("[->+>-<<]", &saturate)[0],
+        Op::MulLoop(..)
+    ));
+    assert!(matches!(
+        optimize("[--->+<]", &saturate)[0],
+        Op::MulLoop(3, _, _)
+    ));
+
+    let trap = Options {
+        overflow: Overflow::Trap,
+        ..Options::default()
+    };
+    assert!(matches!(optimize("[-->+<]", &trap)[0], Op::Loop(..)));
+}
+
+#[test]
+fn test_modular_inverse() {
+    for &odd in &[1, 3, 5, 255, 12345, u64::MAX] {
+        assert_eq!(odd.wrapping_mul(modular_inverse(odd)), 1);
+    }
+}
+
+#[test]
+fn test_scan() {
+    let options = Options::default();
+    // reads keep the loops from being dead
+    let ast = crate::parser::parse("[>>>>],[<],[>>><]").unwrap();
+    let p = |column| Position { line: 1, column };
+
+    assert_eq!(
+        optimize(crate::ir::lower(&ast, &options), &options),
+        vec![
+            Op::Scan(4, p(1)),
+            Op::GetChar(0, p(7)),
+            Op::Scan(-1, p(8)),
+            Op::GetChar(0, p(11)),
+            Op::Scan(2, p(12)),
+        ]
+    );
+}
+
+#[test]
+fn test_clear_loop() {
+    let optimize = |program: &str, overflow: Overflow| {
+        let options = Options {
+            overflow,
+            ..Options::default()
+        };
+        let ast = crate::parser::parse(program).unwrap();
+        optimize(crate::ir::lower(&ast, &options), &options)
+    };
+    let p = |column| Position { line: 1, column };
+
+    assert_eq!(
+        optimize("[-],[+],[+++],[--]", Overflow::Wrap),
+        [
+            Op::Clear(1, p(2)),
+            Op::GetChar(0, p(4)),
+            Op::Clear(1, p(6)),
+            Op::GetChar(0, p(8)),
+            Op::Clear(1, p(10)),
+            Op::GetChar(0, p(14)),
+            Op::Clear(2, p(16)),
+        ]
+    );
+    assert_eq!(optimize("[+-]", Overflow::Wrap), [Op::Clear(0, p(2))]);
+    assert_eq!(
+        optimize("+[]", Overflow::Wrap),
+        [Op::Add(0, 1, p(1)), Op::Clear(0, p(2))]
+    );
+
+    assert_eq!(optimize("[---]", Overflow::Saturate), [Op::Clear(1, p(2))]);
+    assert!(matches!(
+        optimize("[+]", Overflow::Saturate)[0],
+        Op::Loop(..)
+    ));
+    assert_eq!(optimize("[---]", Overflow::Trap), [Op::Clear(3, p(2))]);
+    assert_eq!(optimize(",[]", Overflow::Trap)[1], Op::Clear(0, p(2)));
+}
+
+#[test]
+fn test_canonicalize() {
+    let optimize = |program: &str, overflow: Overflow| {
+        let options = Options {
+            overflow,
+            ..Options::default()
+        };
+        let ast = crate::parser::parse(program).unwrap();
+        optimize(crate::ir::lower(&ast, &options), &options)
+    };
+    let p = |column| Position { line: 1, column };
+
+    assert_eq!(optimize("+++---++", Overflow::Wrap), [Op::Add(0, 2, p(7))]);
+    assert_eq!(optimize("+>+<-", Overflow::Wrap), [Op::Add(1, 1, p(3))]);
+    assert_eq!(optimize("[-]+++++", Overflow::Wrap), [Op::Set(0, 5, p(2))]);
+    assert_eq!(optimize("[-]---", Overflow::Wrap), [Op::Set(0, 253, p(2))]);
+
+    assert_eq!(
+        optimize("[-]---+", Overflow::Saturate),
+        [Op::Set(0, 1, p(2))]
+    );
+    assert_eq!(optimize("++--", Overflow::Saturate).len(), 2);
+    assert_eq!(
+        optimize("[-]-", Overflow::Trap),
+        [Op::Clear(1, p(2)), Op::Sub(0, 1, p(4))]
+    );
+}
+
+#[test]
+fn test_propagate() {
+    let options = Options::default();
+    let optimize = |program: &str| {
+        let ast = crate::parser::parse(program).unwrap();
+        optimize(crate::ir::lower(&ast, &options), &options)
+    };
+    let p = |column| Position { line: 1, column };
+
+    // the current cell is zero after a loop
+    assert_eq!(
+        optimize("[.][-][>]"),
+        [Op::Loop(vec![Op::PutChar(0, p(2))], p(1))]
+    );
+
+    let body = vec![Op::PutChar(0, p(6)), Op::Sub(0, 1, p(7))];
+    assert_eq!(
+        optimize("[.]+[.-]"),
+        [
+            Op::Loop(vec![Op::PutChar(0, p(2))], p(1)),
+            Op::Add(0, 1, p(4)),
+            Op::EnteredLoop(body.clone(), p(5)),
+        ]
+    );
+
+    // every cell is zero at the start of a blank tape
+    let options = Options {
+        prefix_steps: 0,
+        ..Options::default()
+    };
+    let blank = |program: &str| {
+        let ast = crate::parser::parse(program).unwrap();
+        specialize_blank(&ast, &options)
+    };
+    assert_eq!(
+        blank("[.]+[.-]"),
+        [Op::Add(0, 1, p(4)), Op::EnteredLoop(body, p(5))]
+    );
+    assert_eq!(blank(">[-]<[.]"), []);
+    assert_eq!(blank("+>[-]<+"), [Op::Add(0, 2, p(1))]);
+}
+
+#[test]
+fn test_specialize_blank() {
+    let blank = |program: &str, prefix_steps| {
+        let options = Options {
+            prefix_steps,
+            ..Options::default()
+        };
+        let ast = crate::parser::parse(program).unwrap();
+        specialize_blank(&ast, &options)
+    };
+    let p = |column| Position { line: 1, column };
+
+    // runs up to the first input
+    assert_eq!(
+        blank("++[>+++<-]>.,.", 100),
+        [
+            Op::Output(vec![6], p(12)),
+            Op::Tape(vec![0, 6], p(1)),
+            Op::GetChar(1, p(13)),
+            Op::PutChar(1, p(14)),
+            Op::Move(1, p(1)),
+        ]
+    );
+
+    // continues the loop out of steps, which is then known to end
+    assert_eq!(
+        blank("+[>+<-]", 3),
+        [
+            Op::Tape(vec![1], p(1)),
+            Op::Add(1, 1, p(4)),
+            Op::Sub(0, 1, p(6)),
+        ]
+    );
+}
@@ -0,0 +1,252 @@
+//! Options of compilation and execution.
+
+use std::str::FromStr;
+
+/// Default number of cells of the tape.
+pub const DEFAULT_TAPE_SIZE: usize = 30000;
+
+/// Default number of steps of a program evaluated at compile time.
+pub const DEFAULT_PREFIX_STEPS: u64 = 1_000_000;
+
+/// Width of a cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+            CellWidth::U64 => 64,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// Largest value of a cell.
+    pub fn max_value(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+}
+
+/// Semantics of cell arithmetic beyond the bounds of a cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wraps around modulo 2^width.
+    Wrap,
+    /// Clamps to 0 and the largest value.
+    Saturate,
+    /// Stops the program with
+    /// [`RuntimeError::Overflow`](crate::runtime::RuntimeError::Overflow) or
+    /// [`RuntimeError::Underflow`](crate::runtime::RuntimeError::Underflow).
+    Trap,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(format!("unknown overflow mode: {}", s)),
+        }
+    }
+}
+
+/// Value read by `,` at the end of input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eof {
+    /// Sets the cell to 0.
+    Zero,
+    /// Sets the cell to its largest value (-1).
+    Max,
+    /// Leaves the cell unchanged.
+    Unchanged,
+}
+
+impl Eof {
+    /// Value of a cell holding `current` after `,` at the end of input.
+    pub fn value(self, current: u64, cell_width: CellWidth) -> u64 {
+        match self {
+            Eof::Zero => 0,
+            Eof::Max => cell_width.max_value(),
+            Eof::Unchanged => current,
+        }
+    }
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Eof::Zero),
+            "max" => Ok(Eof::Max),
+            "unchanged" => Ok(Eof::Unchanged),
+            _ => Err(format!("unknown EOF behavior: {}", s)),
+        }
+    }
+}
+
+/// Buffering of the output of a program.
+///
+/// The output is always flushed when the program reads input and when it exits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Buffering {
+    /// Flushes like [`Buffering::Line`] when the standard output is a terminal, and like
+    /// [`Buffering::Full`] otherwise.
+    Auto,
+    /// Flushes when the buffer is full.
+    Full,
+    /// Flushes on every newline, for interactive use.
+    Line,
+    /// Flushes every byte.
+    None,
+}
+
+impl FromStr for Buffering {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Buffering::Auto),
+            "full" => Ok(Buffering::Full),
+            "line" => Ok(Buffering::Line),
+            "none" => Ok(Buffering::None),
+            _ => Err(format!("unknown buffering: {}", s)),
+        }
+    }
+}
+
+/// Backend executing programs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Compiles the program with LLVM.
+    #[cfg(feature = "llvm")]
+    Llvm,
+    /// Compiles the program with Cranelift.
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+    /// Interprets the AST without compilation.
+    Interpreter,
+}
+
+impl Default for Backend {
+    #[cfg(feature = "llvm")]
+    fn default() -> Self {
+        Backend::Llvm
+    }
+
+    #[cfg(not(feature = "llvm"))]
+    fn default() -> Self {
+        Backend::Interpreter
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "llvm")]
+            "llvm" => Ok(Backend::Llvm),
+            #[cfg(not(feature = "llvm"))]
+            "llvm" => Err("bf-rs is built without the llvm feature".into()),
+            #[cfg(feature = "cranelift")]
+            "cranelift" => Ok(Backend::Cranelift),
+            #[cfg(not(feature = "cranelift"))]
+            "cranelift" => Err("bf-rs is built without the cranelift feature".into()),
+            "interpreter" => Ok(Backend::Interpreter),
+            _ => Err(format!("unknown backend: {}", s)),
+        }
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            "64" => Ok(CellWidth::U64),
+            _ => Err(format!("unsupported cell width: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Backend executing the program.
+    pub backend: Backend,
+    /// Runs the LLVM optimization pipeline and generates code at the aggressive level
+    /// (optimizes for speed with Cranelift).
+    pub optimized: bool,
+    /// Number of cells of the tape.
+    ///
+    /// The tape is allocated zeroed on the heap, so large tapes only take memory for the
+    /// pages actually touched by the program.
+    pub tape_size: usize,
+    /// Width of a cell.
+    pub cell_width: CellWidth,
+    /// Semantics of cell arithmetic beyond the bounds of a cell.
+    pub overflow: Overflow,
+    /// Value read by `,` at the end of input.
+    pub eof: Eof,
+    /// Buffering of the output.
+    pub buffering: Buffering,
+    /// Checks every pointer move against the bounds of the tape, stopping the program with
+    /// [`RuntimeError::OutOfBounds`](crate::runtime::RuntimeError::OutOfBounds) instead of
+    /// accessing memory outside of it.
+    pub checked: bool,
+    /// Counts the executed instructions into
+    /// [`MachineState::counts`](crate::runtime::MachineState::counts).
+    ///
+    /// Disables the loop optimizations, which skip the instructions of loop bodies.
+    pub count_instructions: bool,
+    /// Number of instructions and loop iterations run at compile time before the first
+    /// input, whose output and tape are built into compiled programs started on a blank
+    /// tape. 0 disables it.
+    pub prefix_steps: u64,
+    /// Reports the progress of compilation on the standard error.
+    pub verbose: bool,
+}
+
+impl Options {
+    /// Writes a progress message to stderr in verbose mode.
+    #[cfg(any(feature = "llvm", feature = "cranelift"))]
+    pub(crate) fn log(&self, message: &str) {
+        if self.verbose {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            optimized: false,
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            overflow: Overflow::Wrap,
+            eof: Eof::Max,
+            buffering: Buffering::Auto,
+            checked: false,
+            count_instructions: false,
+            prefix_steps: DEFAULT_PREFIX_STEPS,
+            verbose: false,
+        }
+    }
+}
@@ -1,15 +1,91 @@
 use crate::{ice, Error, Result};
 use pest::{iterators::Pair, Parser};
 
+/// Position of an operation in the source, both 1-based.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Position {
+    /// Packs the position into a `u64` to pass it to the runtime.
+    #[cfg(any(feature = "llvm", feature = "cranelift"))]
+    pub(crate) fn to_raw(self) -> u64 {
+        (u64::from(self.line) << 32) | u64::from(self.column)
+    }
+
+    #[cfg_attr(not(any(feature = "llvm", feature = "cranelift")), allow(dead_code))]
+    pub(crate) fn from_raw(raw: u64) -> Self {
+        Self {
+            line: (raw >> 32) as u32,
+            column: raw as u32,
+        }
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BfAST {
-    LoopBlock(Vec<BfAST>),
-    AddOp(usize),
-    SubOp(usize),
-    AddPtr(usize),
-    SubPtr(usize),
-    PutChar,
-    GetChar,
+    LoopBlock(Vec<BfAST>, Position),
+    AddOp(usize, Position),
+    SubOp(usize, Position),
+    AddPtr(usize, Position),
+    SubPtr(usize, Position),
+    PutChar(Position),
+    GetChar(Position),
+}
+
+impl BfAST {
+    pub fn position(&self) -> Position {
+        match *self {
+            BfAST::LoopBlock(_, p)
+            | BfAST::AddOp(_, p)
+            | BfAST::SubOp(_, p)
+            | BfAST::AddPtr(_, p)
+            | BfAST::SubPtr(_, p)
+            | BfAST::PutChar(p)
+            | BfAST::GetChar(p) => p,
+        }
+    }
+}
+
+/// Start offsets of the lines of the source, used to look up positions.
+struct LineIndex<'a> {
+    source: &'a str,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        Self {
+            source,
+            line_starts,
+        }
+    }
+
+    fn position(&self, p: &Pair<'_, Rule>) -> Position {
+        let offset = p.as_span().start();
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let column = self.source[self.line_starts[line]..offset].chars().count();
+
+        Position {
+            line: line as u32 + 1,
+            column: column as u32 + 1,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -18,7 +94,7 @@ struct BfParser;
 
 pub type ParseError = pest::error::Error<Rule>;
 
-fn visit_symbol(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
+fn visit_symbol(p: Pair<'_, Rule>, lines: &LineIndex<'_>, v: &mut Vec<BfAST>) -> Result<()> {
     if p.as_rule() != Rule::symbol {
         ice!(
             "wrong visitor: expected symbol, but given {:?}",
@@ -30,28 +106,30 @@ fn visit_symbol(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
     let inner: Vec<_> = inner.collect();
 
     for tok in &inner {
+        let position = lines.position(tok);
+
         match tok.as_rule() {
             Rule::increment => {
-                v.push(BfAST::AddOp(inner.len()));
+                v.push(BfAST::AddOp(inner.len(), position));
                 break;
             }
             Rule::decrement => {
-                v.push(BfAST::SubOp(inner.len()));
+                v.push(BfAST::SubOp(inner.len(), position));
                 break;
             }
             Rule::pointer_increment => {
-                v.push(BfAST::AddPtr(inner.len()));
+                v.push(BfAST::AddPtr(inner.len(), position));
                 break;
             }
             Rule::pointer_decrement => {
-                v.push(BfAST::SubPtr(inner.len()));
+                v.push(BfAST::SubPtr(inner.len(), position));
                 break;
             }
             Rule::print_character => {
-                v.push(BfAST::PutChar);
+                v.push(BfAST::PutChar(position));
             }
             Rule::get_character => {
-                v.push(BfAST::GetChar);
+                v.push(BfAST::GetChar(position));
             }
             _ => {
                 ice!("unexpected token while visiting block: {:?}", tok);
@@ -62,7 +140,7 @@ fn visit_symbol(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
     Ok(())
 }
 
-fn visit_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
+fn visit_block(p: Pair<'_, Rule>, lines: &LineIndex<'_>, v: &mut Vec<BfAST>) -> Result<()> {
     if p.as_rule() != Rule::block {
         ice!("wrong visitor: expected block, but given {:?}", p.as_rule());
     }
@@ -70,10 +148,10 @@ fn visit_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
     for tok in p.into_inner() {
         match tok.as_rule() {
             Rule::symbol => {
-                visit_symbol(tok, v)?;
+                visit_symbol(tok, lines, v)?;
             }
             Rule::loop_block => {
-                visit_loop_block(tok, v)?;
+                visit_loop_block(tok, lines, v)?;
             }
             _ => {
                 ice!("unexpected token while visiting block: {:?}", tok);
@@ -84,7 +162,7 @@ fn visit_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
     Ok(())
 }
 
-fn visit_loop_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
+fn visit_loop_block(p: Pair<'_, Rule>, lines: &LineIndex<'_>, v: &mut Vec<BfAST>) -> Result<()> {
     if p.as_rule() != Rule::loop_block {
         ice!(
             "wrong visitor: expected loop_block, but given {:?}",
@@ -92,6 +170,8 @@ fn visit_loop_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
         );
     }
 
+    let position = lines.position(&p);
+
     for tok in p.into_inner() {
         match tok.as_rule() {
             Rule::left_brace => {
@@ -102,8 +182,8 @@ fn visit_loop_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
             }
             Rule::block => {
                 let mut v2 = vec![];
-                visit_block(tok, &mut v2)?;
-                v.push(BfAST::LoopBlock(v2));
+                visit_block(tok, lines, &mut v2)?;
+                v.push(BfAST::LoopBlock(v2, position));
             }
             _ => {
                 ice!("unexpected token while visiting loop block: {:?}", tok);
@@ -114,7 +194,7 @@ fn visit_loop_block(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
     Ok(())
 }
 
-fn visit_program(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
+fn visit_program(p: Pair<'_, Rule>, lines: &LineIndex<'_>, v: &mut Vec<BfAST>) -> Result<()> {
     if p.as_rule() != Rule::program {
         ice!(
             "wrong visitor: expected program, but given {:?}",
@@ -126,7 +206,7 @@ fn visit_program(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
         match tok.as_rule() {
             Rule::EOI => {}
             Rule::block => {
-                visit_block(tok, v)?;
+                visit_block(tok, lines, v)?;
             }
             _ => {
                 ice!("unexpected token while visiting program: {:?}", tok);
@@ -138,13 +218,14 @@ fn visit_program(p: Pair<'_, Rule>, v: &mut Vec<BfAST>) -> Result<()> {
 }
 
 pub fn parse<P: AsRef<str>>(program: P) -> Result<Vec<BfAST>> {
-    let mut pairs = BfParser::parse(Rule::program, program.as_ref())?;
+    let program_str = program.as_ref();
+    let mut pairs = BfParser::parse(Rule::program, program_str)?;
     let program = pairs
         .next()
         .ok_or_else(|| Error::ice("no matching program"))?;
 
     let mut program_out = vec![];
-    visit_program(program, &mut program_out)?;
+    visit_program(program, &LineIndex::new(program_str), &mut program_out)?;
 
     Ok(program_out)
 }
@@ -164,3 +245,18 @@ fn test_parse_hello_world() {
 fn test_parse_fail() {
     parse("[").unwrap();
 }
+
+#[test]
+fn test_parse_positions() {
+    let ast = parse("+\n ab[>>.]").unwrap();
+
+    assert_eq!(ast[0].position(), Position { line: 1, column: 1 });
+
+    assert_eq!(ast[1].position(), Position { line: 2, column: 4 });
+    if let BfAST::LoopBlock(v, _) = &ast[1] {
+        assert_eq!(v[0], BfAST::AddPtr(2, Position { line: 2, column: 5 }));
+        assert_eq!(v[1], BfAST::PutChar(Position { line: 2, column: 7 }));
+    } else {
+        panic!("expected loop block");
+    }
+}
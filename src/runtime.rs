@@ -0,0 +1,503 @@
+//! Runtime support functions called from JIT-compiled programs.
+// the functions called from generated code are only used with the compiling backends
+#![cfg_attr(not(any(feature = "llvm", feature = "cranelift")), allow(dead_code))]
+use crate::options::{Buffering, CellWidth, Eof, Options};
+use crate::parser::Position;
+
+use std::alloc::{self, Layout};
+use std::any::Any;
+use std::ffi::c_void;
+use std::io::{BufRead, BufReader, ErrorKind, IsTerminal, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use thiserror::Error;
+
+/// Errors raised by a running program.
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("{position}: pointer out of bounds: {pointer}")]
+    OutOfBounds { position: Position, pointer: i64 },
+    #[error("{position}: cell overflow")]
+    Overflow { position: Position },
+    #[error("{position}: cell underflow")]
+    Underflow { position: Position },
+    #[error("{position}: I/O error: {source}")]
+    Io {
+        position: Position,
+        source: std::io::Error,
+    },
+}
+
+/// State of the machine after a program has run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MachineState {
+    /// Values of the cells, without the trailing zero cells.
+    pub tape: Vec<u64>,
+    /// Index of the current cell.
+    pub pointer: usize,
+    /// Number of executed instructions, when counted
+    /// (see [`Options::count_instructions`](crate::Options::count_instructions)).
+    pub counts: Option<InstructionCounts>,
+}
+
+/// State of the machine after a program has run, keeping the whole tape.
+///
+/// Reading the tape into a [`MachineState`] scans every cell, so it is left to the callers
+/// which need the state.
+pub(crate) struct FinalState {
+    pub tape: Tape,
+    pub pointer: usize,
+    pub counts: Option<InstructionCounts>,
+}
+
+impl FinalState {
+    pub fn into_machine_state(self) -> MachineState {
+        MachineState {
+            tape: self.tape.cells(),
+            pointer: self.pointer,
+            counts: self.counts,
+        }
+    }
+}
+
+/// Number of executed instructions of each kind.
+///
+/// `[` is counted each time the loop is entered and `]` each time its body completes.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InstructionCounts {
+    pub add: u64,
+    pub sub: u64,
+    pub right: u64,
+    pub left: u64,
+    pub output: u64,
+    pub input: u64,
+    pub loop_start: u64,
+    pub loop_end: u64,
+}
+
+impl InstructionCounts {
+    /// Total number of executed instructions.
+    pub fn total(&self) -> u64 {
+        self.add
+            + self.sub
+            + self.right
+            + self.left
+            + self.output
+            + self.input
+            + self.loop_start
+            + self.loop_end
+    }
+}
+
+/// Entry point `bfrs_lang_start` of a compiled program, returning the final pointer.
+///
+/// Compiled programs may also have an entry point `bfrs_lang_main` specialized for a blank
+/// tape, which is only called when [`is_blank`] holds.
+pub type BfBootstrap = unsafe extern "C" fn(
+    *mut c_void,
+    unsafe extern "C" fn(env: *mut c_void, current: u64) -> u64,
+    unsafe extern "C" fn(env: *mut c_void, c: u64),
+    unsafe extern "C" fn(env: *mut c_void, kind: u32, position: u64, value: i64),
+    *mut u8,
+    i64,
+    *mut u64,
+) -> i64;
+
+/// Runs the entry point of a compiled program with `call`, which is given the environment,
+/// the tape, the initial pointer and the instruction counts to pass to it.
+pub(crate) fn execute_compiled(
+    options: &Options,
+    tape: &[u64],
+    pointer: usize,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    call: impl FnOnce(*mut c_void, *mut u8, i64, *mut u64) -> i64,
+) -> crate::Result<FinalState> {
+    check_initial_state(options, tape, pointer)?;
+
+    let mut env = Environment::new(input, output, options);
+    let mut tape = Tape::new(options.tape_size, options.cell_width, tape)?;
+    let mut counts = InstructionCounts::default();
+
+    let pointer = call(
+        &mut env as *mut Environment<'_> as *mut c_void,
+        tape.as_mut_ptr(),
+        pointer as i64,
+        &mut counts as *mut InstructionCounts as *mut u64,
+    );
+
+    if let Some(payload) = env.take_panic() {
+        panic::resume_unwind(payload);
+    }
+
+    // the output before an error is kept
+    let flushed = env.flush();
+
+    if let Some(message) = env.take_ice() {
+        return Err(crate::Error::ice(message));
+    }
+    if let Some(e) = env.take_error() {
+        return Err(e.into());
+    }
+
+    flushed?;
+
+    Ok(FinalState {
+        tape,
+        pointer: pointer as usize,
+        counts: if options.count_instructions {
+            Some(counts)
+        } else {
+            None
+        },
+    })
+}
+
+/// Whether an initial tape and pointer are the blank start of a program, where every cell
+/// is zero and the pointer is at the first cell.
+pub(crate) fn is_blank(tape: &[u64], pointer: usize) -> bool {
+    pointer == 0 && tape.iter().all(|&cell| cell == 0)
+}
+
+/// Checks that an initial tape and pointer fit on the tape of the options.
+pub(crate) fn check_initial_state(
+    options: &Options,
+    tape: &[u64],
+    pointer: usize,
+) -> crate::Result<()> {
+    if tape.len() > options.tape_size || pointer >= options.tape_size {
+        return Err(crate::Error::InvalidState(format!(
+            "{} cells with the pointer at {} do not fit on a tape of {} cells",
+            tape.len(),
+            pointer,
+            options.tape_size
+        )));
+    }
+    let layout = tape_words(options.tape_size, options.cell_width)
+        .and_then(|words| Layout::array::<u64>(words).ok());
+    if layout.is_none() {
+        return Err(crate::Error::InvalidState(format!(
+            "a tape of {} cells overflows the address space",
+            options.tape_size
+        )));
+    }
+
+    Ok(())
+}
+
+/// Indices of the fields of [`InstructionCounts`] incremented by generated code.
+pub(crate) const COUNT_ADD: u64 = 0;
+pub(crate) const COUNT_SUB: u64 = 1;
+pub(crate) const COUNT_RIGHT: u64 = 2;
+pub(crate) const COUNT_LEFT: u64 = 3;
+pub(crate) const COUNT_OUTPUT: u64 = 4;
+pub(crate) const COUNT_INPUT: u64 = 5;
+pub(crate) const COUNT_LOOP_START: u64 = 6;
+pub(crate) const COUNT_LOOP_END: u64 = 7;
+
+/// Number of u64 words holding a tape of `len` cells, or `None` if its size overflows.
+pub(crate) fn tape_words(len: usize, cell_width: CellWidth) -> Option<usize> {
+    let bytes = len.checked_mul(cell_width.bytes())?;
+    Some(bytes / 8 + usize::from(bytes % 8 != 0))
+}
+
+/// Tape laid out as generated code accesses it, with cells of the native width.
+pub(crate) struct Tape {
+    // u64 words align cells of any width
+    words: Vec<u64>,
+    len: usize,
+    cell_width: CellWidth,
+}
+
+impl Tape {
+    /// Creates a tape of `len` cells, holding `cells` at the beginning and zero after.
+    ///
+    /// Values are truncated to the cell width.
+    /// Fails without aborting if the tape does not fit in memory.
+    pub fn new(len: usize, cell_width: CellWidth, cells: &[u64]) -> crate::Result<Self> {
+        let too_large = || {
+            crate::Error::InvalidState(format!("a tape of {} cells does not fit in memory", len))
+        };
+        let words = tape_words(len, cell_width).ok_or_else(too_large)?;
+        let layout = Layout::array::<u64>(words).map_err(|_| too_large())?;
+
+        let words = if words == 0 {
+            Vec::new()
+        } else {
+            // zeroed by the allocator, so only the pages the program touches take memory
+            let ptr = unsafe { alloc::alloc_zeroed(layout) } as *mut u64;
+            if ptr.is_null() {
+                return Err(too_large());
+            }
+            unsafe { Vec::from_raw_parts(ptr, words, words) }
+        };
+        let mut tape = Self {
+            words,
+            len,
+            cell_width,
+        };
+
+        for (i, &value) in cells.iter().enumerate().take(len) {
+            tape.set(i, value);
+        }
+
+        Ok(tape)
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.words.as_mut_ptr() as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Value of the cell at `index`.
+    pub fn get(&self, index: usize) -> u64 {
+        assert!(index < self.len, "cell {} out of the tape", index);
+        let ptr = self.words.as_ptr();
+
+        // in bounds, and the u64 words align cells of any width
+        unsafe {
+            match self.cell_width {
+                CellWidth::U8 => u64::from(*(ptr as *const u8).add(index)),
+                CellWidth::U16 => u64::from(*(ptr as *const u16).add(index)),
+                CellWidth::U32 => u64::from(*(ptr as *const u32).add(index)),
+                CellWidth::U64 => *ptr.add(index),
+            }
+        }
+    }
+
+    /// Sets the cell at `index`, truncating the value to the cell width.
+    pub fn set(&mut self, index: usize, value: u64) {
+        assert!(index < self.len, "cell {} out of the tape", index);
+        let ptr = self.words.as_mut_ptr();
+
+        unsafe {
+            match self.cell_width {
+                CellWidth::U8 => *(ptr as *mut u8).add(index) = value as u8,
+                CellWidth::U16 => *(ptr as *mut u16).add(index) = value as u16,
+                CellWidth::U32 => *(ptr as *mut u32).add(index) = value as u32,
+                CellWidth::U64 => *ptr.add(index) = value,
+            }
+        }
+    }
+
+    /// Values of the cells, without the trailing zero cells.
+    pub fn cells(&self) -> Vec<u64> {
+        let width = self.cell_width.bytes();
+        let end = self
+            .bytes()
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |i| i / width + 1);
+
+        (0..end).map(|i| self.get(i)).collect()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        let bytes = self.len * self.cell_width.bytes();
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const u8, bytes) }
+    }
+}
+
+/// Size of the output buffer, flushed when full.
+const OUTPUT_BUFFER_SIZE: usize = 8192;
+
+/// Kinds of [`RuntimeError`] raised by generated code through `bfrs_runtime_error`.
+pub(crate) const ERROR_OUT_OF_BOUNDS: u32 = 0;
+pub(crate) const ERROR_OVERFLOW: u32 = 1;
+pub(crate) const ERROR_UNDERFLOW: u32 = 2;
+pub(crate) const ERROR_IO: u32 = 3;
+
+/// I/O environment of a running program.
+///
+/// A pointer to the environment is passed to `bfrs_lang_start` and handed back
+/// to the runtime functions on every `,` and `.`. The input is read ahead into a
+/// buffer, so bytes after the last `,` may be consumed from `input`.
+#[repr(C)]
+pub struct Environment<'a> {
+    // read by the generated code after I/O; must stay the first field
+    failed: bool,
+    input: BufReader<&'a mut dyn Read>,
+    output: &'a mut dyn Write,
+    output_buffer: Vec<u8>,
+    io_error: Option<std::io::Error>,
+    panic: Option<Box<dyn Any + Send>>,
+    error: Option<RuntimeError>,
+    // an error kind unknown to the runtime, which must not panic across the FFI boundary
+    ice: Option<String>,
+    eof: Eof,
+    cell_width: CellWidth,
+    buffering: Buffering,
+}
+
+impl<'a> Environment<'a> {
+    pub fn new(input: &'a mut dyn Read, output: &'a mut dyn Write, options: &Options) -> Self {
+        Self {
+            failed: false,
+            input: BufReader::new(input),
+            output,
+            output_buffer: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+            io_error: None,
+            panic: None,
+            error: None,
+            ice: None,
+            eof: options.eof,
+            cell_width: options.cell_width,
+            buffering: match options.buffering {
+                Buffering::Auto if std::io::stdout().is_terminal() => Buffering::Line,
+                Buffering::Auto => Buffering::Full,
+                buffering => buffering,
+            },
+        }
+    }
+
+    /// Writes the buffered output of the program.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.write_all(&self.output_buffer)?;
+        self.output_buffer.clear();
+
+        self.output.flush()
+    }
+
+    /// Buffers a byte of output.
+    pub(crate) fn write_byte(&mut self, c: u8) -> std::io::Result<()> {
+        self.output_buffer.push(c);
+
+        let flush = match self.buffering {
+            Buffering::Auto | Buffering::Full => self.output_buffer.len() >= OUTPUT_BUFFER_SIZE,
+            Buffering::Line => c == b'\n' || self.output_buffer.len() >= OUTPUT_BUFFER_SIZE,
+            Buffering::None => true,
+        };
+
+        if flush {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a byte of input into the cell holding `current`, applying the EOF behavior
+    /// at the end of input.
+    pub(crate) fn read_cell(&mut self, current: u64) -> std::io::Result<u64> {
+        Ok(match self.read_byte()? {
+            Some(c) => u64::from(c),
+            None => self.eof.value(current, self.cell_width),
+        })
+    }
+
+    /// Reads a byte of input, or `None` at the end of input.
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        // the program may be waiting for the input to be prompted
+        if !self.output_buffer.is_empty() {
+            self.flush()?;
+        }
+
+        loop {
+            let c = match self.input.fill_buf() {
+                Ok(buf) => buf.first().copied(),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if c.is_some() {
+                self.input.consume(1);
+            }
+
+            return Ok(c);
+        }
+    }
+
+    /// Records an I/O error; the generated code reports it with the position of the
+    /// instruction through `bfrs_runtime_error`.
+    fn fail(&mut self, e: std::io::Error) {
+        self.failed = true;
+        self.io_error = Some(e);
+    }
+
+    /// Runs `f` on the environment, catching panics of the reader or writer, which must
+    /// not unwind through the generated code. The panic is resumed by [`Environment::take_panic`].
+    fn guard<T>(&mut self, default: T, f: impl FnOnce(&mut Self) -> std::io::Result<T>) -> T {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => {
+                self.fail(e);
+                default
+            }
+            Err(payload) => {
+                self.fail(std::io::Error::other("I/O panicked"));
+                self.panic = Some(payload);
+                default
+            }
+        }
+    }
+
+    /// Takes the panic raised during a runtime call, if any.
+    pub fn take_panic(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.panic.take()
+    }
+
+    /// Takes the error which stopped the program, if any.
+    pub fn take_error(&mut self) -> Option<RuntimeError> {
+        self.error.take()
+    }
+
+    /// Takes the internal compiler error raised by the generated code, if any.
+    pub fn take_ice(&mut self) -> Option<String> {
+        self.ice.take()
+    }
+}
+
+/// Reads a byte into the cell holding `current`.
+pub(crate) unsafe extern "C" fn bfrs_get_char(env: *mut c_void, current: u64) -> u64 {
+    let env = &mut *(env as *mut Environment<'_>);
+
+    env.guard(current, |env| env.read_cell(current))
+}
+
+/// Writes the lowest byte of the cell.
+pub(crate) unsafe extern "C" fn bfrs_print_char(env: *mut c_void, c: u64) {
+    let env = &mut *(env as *mut Environment<'_>);
+
+    env.guard((), |env| env.write_byte(c as u8))
+}
+
+/// Returns the index of the last zero byte of the tape up to the pointer, or -1 if there is
+/// none, for the backward scans of byte cells.
+pub(crate) unsafe extern "C" fn bfrs_scan_back(tape: *const u8, pointer: i64) -> i64 {
+    let cells = std::slice::from_raw_parts(tape, pointer as usize + 1);
+
+    cells.iter().rposition(|&c| c == 0).map_or(-1, |i| i as i64)
+}
+
+/// Records a runtime error; the generated code returns right after the call.
+pub(crate) unsafe extern "C" fn bfrs_runtime_error(
+    env: *mut c_void,
+    kind: u32,
+    position: u64,
+    value: i64,
+) {
+    let env = &mut *(env as *mut Environment<'_>);
+    let position = Position::from_raw(position);
+
+    env.error = match kind {
+        ERROR_OUT_OF_BOUNDS => Some(RuntimeError::OutOfBounds {
+            position,
+            pointer: value,
+        }),
+        ERROR_OVERFLOW => Some(RuntimeError::Overflow { position }),
+        ERROR_UNDERFLOW => Some(RuntimeError::Underflow { position }),
+        ERROR_IO => Some(RuntimeError::Io {
+            position,
+            source: env
+                .io_error
+                .take()
+                .unwrap_or_else(|| ErrorKind::Other.into()),
+        }),
+        _ => {
+            env.ice = Some(format!("unknown runtime error kind: {}", kind));
+            None
+        }
+    };
+}